@@ -1,19 +1,18 @@
 #![allow(unused_variables, unused_assignments)]
 
-use anyhow::{bail, Result};
+use anyhow::Result;
 use rustyline::error::ReadlineError;
 use rustyline::DefaultEditor;
 
 use clap::Parser;
 use colored::Colorize;
 use coyotec::ast::node::{display_tree, NodeType};
-use coyotec::ast::tree::ValueType;
 use coyotec::ast::Node;
 use coyotec::compiler::compile;
-use coyotec::datatypes::datatype::DataType;
 use coyotec::generator::IrGenerator;
-use coyotec::lexer::{lex, SourceType};
+use coyotec::lexer::{lex_file, LexMode, SourceType};
 use coyotec::parse::parser;
+use coyotec::sourcemap::SourceMap;
 use coyotec::tokens::Token;
 use cvm::vm;
 use cvm::vm::Vm;
@@ -55,7 +54,7 @@ pub fn run() -> Result<()> {
     if let Some(file) = &cli.file {
         println!("Loading file: {}", file);
         let bytecode = load_file(file)?;
-        vm::execute(bytecode);
+        vm::execute(bytecode)?;
     }
 
     // Check if debug mode is enabled
@@ -84,7 +83,7 @@ fn load_file(file: &str) -> Result<Vec<u8>> {
     compile(&contents, SourceType::File(file.to_string()))
 }
 
-fn repl<'a>() -> Result<()> {
+fn repl() -> Result<()> {
     let mut rl = DefaultEditor::new()?;
     if rl.load_history("history.txt").is_err() {
         println!("No previous history.");
@@ -95,6 +94,8 @@ fn repl<'a>() -> Result<()> {
     let mut generator = IrGenerator::new(&ast);
     let mut parser = parser::Parser::new(vec![], "".to_string());
     let mut tokens: Vec<Token> = Vec::new();
+    // Every line entered is kept as its own file so that tokens can point back at it
+    let mut sources = SourceMap::new();
     loop {
         let readline = rl.readline(">> ");
         match readline {
@@ -104,24 +105,34 @@ fn repl<'a>() -> Result<()> {
                 if line == "exit" || line == "quit" || line == "/q" {
                     break;
                 }
-                tokens = lex(&line, SourceType::Interactive)?;
+                let file_id = sources.add(SourceType::Interactive, line.clone());
+                tokens = match lex_file(&sources, file_id, LexMode::Normal) {
+                    Ok(tokens) => tokens,
+                    Err(err) => {
+                        println!("{}", sources.render_error(&err));
+                        continue;
+                    }
+                };
                 parser.add_tokens(tokens, line);
 
-                if let Ok(node) = parser.parse() {
-                    display_tree(&node);
-
-                    // Generate the assembly code
-                    generator.generate(&node);
-                    let asm = format!("{}", generator);
-                    println!("{}", asm);
-
-                    // Assemble the assembly code into bytecode
-                    let bytecode = assemble(&asm);
-                    vm.code = bytecode;
-                    println!("{:?}", &vm.code);
-                    vm.run()
-                } else {
-                    bail!("Error parsing");
+                match parser.parse() {
+                    Ok(node) => {
+                        display_tree(&node);
+
+                        // Generate the assembly code
+                        generator.generate(&node);
+                        let asm = format!("{}", generator);
+                        println!("{}", asm);
+
+                        // Assemble the assembly code into bytecode
+                        let bytecode = assemble(&asm);
+                        vm.code = bytecode;
+                        println!("{:?}", &vm.code);
+                        if let Err(err) = vm.run() {
+                            println!("{err}");
+                        }
+                    }
+                    Err(err) => println!("{}", sources.render_error(&err)),
                 }
             }
             Err(ReadlineError::Interrupted) => {
@@ -0,0 +1,35 @@
+#!/bin/sh
+# Run the lexer benchmark against the lexer from before it read straight out of the
+# source by byte offset, then against the current one.
+#
+# The old lexer is built from the commit pinned below, checked out into a scratch
+# worktree with this benchmark copied in, so nothing of it is kept in the tree.
+#
+# Run with `sh coyotec/benches/compare.sh` from anywhere in the repository.
+set -e
+
+# The last commit before the lexer was rewritten
+BASELINE=ad821d2d72205d537a9349c2383f0618eba161e9
+
+root=$(git rev-parse --show-toplevel)
+tree=$(mktemp -d)
+git -C "$root" worktree add --quiet --detach "$tree" "$BASELINE"
+trap 'git -C "$root" worktree remove --force "$tree"' EXIT
+
+mkdir -p "$tree/coyotec/benches"
+cp "$root/coyotec/benches/lexer.rs" "$tree/coyotec/benches/lexer.rs"
+cat >> "$tree/coyotec/Cargo.toml" <<'EOF'
+
+[[bench]]
+name = "lexer"
+harness = false
+
+[lints.rust]
+unexpected_cfgs = { level = "warn", check-cfg = ["cfg(coyote_baseline)"] }
+EOF
+
+echo "baseline ($BASELINE)"
+(cd "$tree" && RUSTFLAGS="--cfg coyote_baseline" cargo bench --quiet -p coyotec --bench lexer)
+echo
+echo "current"
+(cd "$root" && cargo bench --quiet -p coyotec --bench lexer)
@@ -0,0 +1,63 @@
+//! Lexer throughput on a large generated script.
+//!
+//! Run with `cargo bench -p coyotec --bench lexer`, or with `sh benches/compare.sh` to
+//! also run it against the lexer from before it read straight out of the source by
+//! byte offset. That one has no `lex_stream`, so it's left out when `coyote_baseline`
+//! is set.
+use coyotec::lexer::{lex, SourceType};
+#[cfg(not(coyote_baseline))]
+use coyotec::lexer::{lex_stream, LexMode};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const LINES: usize = 50_000;
+const ROUNDS: usize = 10;
+
+/// Build a script that looks like the machine-generated ones: lots of short
+/// declarations, arithmetic, strings and comments
+fn script() -> String {
+    let mut code = String::new();
+    for i in 0..LINES {
+        let line = match i % 5 {
+            0 => format!("let value_{i} = {i} * 2 + 0x{i:x} - 1.5e3\n"),
+            1 => format!("print \"row {i}: ${{value_{}}}\\n\"\n", i - 1),
+            2 => format!("let total = total + value_{} // running sum\n", i - 2),
+            3 => format!("/* block {i} */ let flag_{i} = true and not false\n"),
+            _ => format!("let name_{i} = r\"raw\\text\" + \"{i}\"\n"),
+        };
+        code.push_str(&line);
+    }
+    code
+}
+
+/// Time `f` over several rounds and report the best throughput
+fn measure(name: &str, code: &str, f: impl Fn(&str) -> usize) {
+    let mut best = Duration::MAX;
+    let mut count = 0;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        count = black_box(f(black_box(code)));
+        best = best.min(start.elapsed());
+    }
+    let seconds = best.as_secs_f64();
+    println!(
+        "{name:<10} {count} tokens in {:>8.2?}  {:>7.1} MB/s  {:>6.2} M tokens/s",
+        best,
+        code.len() as f64 / seconds / 1e6,
+        count as f64 / seconds / 1e6,
+    );
+}
+
+fn main() {
+    let code = script();
+    println!("lexing {} bytes, best of {ROUNDS} rounds", code.len());
+    measure("lex", &code, |code| {
+        lex(code, SourceType::Test).unwrap().len()
+    });
+    #[cfg(not(coyote_baseline))]
+    measure("lex_stream", &code, |code| {
+        lex_stream(code, SourceType::Test, 0, LexMode::Normal)
+            .inspect(|token| assert!(token.is_ok()))
+            .count()
+    });
+}
@@ -1,5 +1,5 @@
 #![allow(dead_code, unused_variables)]
-/// Register allocator using the Graph Coloring Algorithm
+//! Register allocator using the Graph Coloring Algorithm
 
 pub struct Registers {
     stack: Vec<bool>,
@@ -1,3 +1,4 @@
+#![allow(dead_code)]
 pub const EXPR: usize = 1;
 pub const STATEMENT: usize = 2;
 
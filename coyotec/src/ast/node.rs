@@ -1,7 +1,7 @@
-use crate::ast::tree::ValueType;
+use crate::datatypes::datatype::TypeAnnotation;
 use crate::tokens::BaseType::Undefined;
-use crate::tokens::{BaseType, Location, Token};
-use std::fmt::{write, Display, Formatter};
+use crate::tokens::{BaseType, Token};
+use std::fmt::Display;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum BinOp {
@@ -9,7 +9,20 @@ pub enum BinOp {
     Sub,
     Mul,
     Div,
+    IntDiv,
+    Mod,
     Pow,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
     And,
     Or,
 }
@@ -21,7 +34,20 @@ impl Display for BinOp {
             BinOp::Sub => write!(f, "sub"),
             BinOp::Mul => write!(f, "mul"),
             BinOp::Div => write!(f, "div"),
+            BinOp::IntDiv => write!(f, "idiv"),
+            BinOp::Mod => write!(f, "mod"),
             BinOp::Pow => write!(f, "pow"),
+            BinOp::BitAnd => write!(f, "band"),
+            BinOp::BitOr => write!(f, "bor"),
+            BinOp::BitXor => write!(f, "bxor"),
+            BinOp::Shl => write!(f, "shl"),
+            BinOp::Shr => write!(f, "shr"),
+            BinOp::Eq => write!(f, "eq"),
+            BinOp::Ne => write!(f, "ne"),
+            BinOp::Lt => write!(f, "lt"),
+            BinOp::Le => write!(f, "le"),
+            BinOp::Gt => write!(f, "gt"),
+            BinOp::Ge => write!(f, "ge"),
             BinOp::And => write!(f, "and"),
             BinOp::Or => write!(f, "or"),
         }
@@ -32,32 +58,126 @@ impl Display for BinOp {
 pub enum UnOp {
     Neg,
     Not,
+    BitNot,
 }
 impl Display for UnOp {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             UnOp::Neg => write!(f, "neg"),
             UnOp::Not => write!(f, "not"),
+            UnOp::BitNot => write!(f, "bnot"),
         }
     }
 }
+/// What a function node knows about itself besides its parameters and body
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionDef {
+    pub name: Option<String>,
+    // The variables it uses from the functions around it, in the order they're
+    // kept in the closure
+    pub captures: Vec<String>,
+}
+
+/// A struct literal's type and the fields its children set, in declaration order.
+/// Each field has the type it was declared with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StructLiteral {
+    pub name: String,
+    pub fields: Vec<(String, BaseType)>,
+}
+
+/// The variant of an enum that a value is made from, such as `Shape.Circle`. The
+/// children are the values it carries, and the payload has the types they were
+/// declared with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    pub enum_name: String,
+    pub name: String,
+    // The variant's position in the enum
+    pub tag: usize,
+    pub payload: Vec<BaseType>,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum NodeType {
     Root,
     Undefined,
-    Integer(f64),
+    Integer(i64),
     Float(f64),
     Boolean(bool),
+    Char(char),
+    Nil,
     Text(Box<String>),
+    Interpolation,
     Ident(Box<String>, Box<NodeType>),
     Array(Box<NodeType>),
+    // An array followed by the index of one of its elements
+    ElementIndex,
+    // The named field of the child
+    FieldAccess(Box<String>),
+    // A value of a struct, such as `Point { x: 1, y: 2 }`
+    StructLiteral(Box<StructLiteral>),
+    // A value of an enum, such as `Shape.Circle(1.5)` or `Shape.Empty`
+    Variant(Box<Variant>),
+    // The integers from the start up to the end, which is included if the flag is
+    // set. Like a binary operator, the children are the end then the start.
+    Range(bool),
     UnaryOp(UnOp),
     BinaryOp(BinOp),
-    Function(Box<Vec<NodeType>>),
+    // The children are the parameters then the body. A named function is a
+    // statement and an anonymous one is an expression.
+    Function(Box<FunctionDef>),
+    // The function then its arguments
+    Call,
     Assignment,
+    // Updates the target with the operator, as in `x += 1`
+    CompoundAssignment(BinOp),
+    // Statements in braces. As an expression its value is the last statement's.
+    Block,
+    // Condition, then block, and an optional else block or chained `if`
+    If,
     // Statements
     Let,
+    // The declaration of a struct, whose fields are kept in the type registry
+    Struct(Box<String>),
+    // The declaration of an enum, whose variants are kept in the type registry
+    Enum(Box<String>),
     Print,
+    // Leaves the function with the child's value, or nil
+    Return,
+    // Condition then body, with the loop's label if it has one
+    While(Option<Box<String>>),
+    // The loop variable, followed by the range or array and the body
+    For(Option<Box<String>>),
+    // A body that repeats until a `break`
+    Loop(Option<Box<String>>),
+    // Leave or restart the innermost loop, or the one with the label
+    Break(Option<Box<String>>),
+    Continue(Option<Box<String>>),
+}
+
+impl NodeType {
+    /// Whether this node is a statement that leaves no value behind
+    pub fn is_statement(&self) -> bool {
+        if let NodeType::Function(def) = self {
+            return def.name.is_some();
+        }
+        matches!(
+            self,
+            NodeType::Let
+                | NodeType::Struct(_)
+                | NodeType::Enum(_)
+                | NodeType::Print
+                | NodeType::Return
+                | NodeType::Assignment
+                | NodeType::CompoundAssignment(_)
+                | NodeType::While(_)
+                | NodeType::For(_)
+                | NodeType::Loop(_)
+                | NodeType::Break(_)
+                | NodeType::Continue(_)
+        )
+    }
 }
 
 impl Display for NodeType {
@@ -68,22 +188,60 @@ impl Display for NodeType {
             NodeType::Integer(i) => write!(f, "Int:{}", i),
             NodeType::Float(float) => write!(f, "Float:{}", float),
             NodeType::Boolean(b) => write!(f, "Boolean:{}", b),
+            NodeType::Char(c) => write!(f, "Char:{:?}", c),
+            NodeType::Nil => write!(f, "nil"),
             NodeType::Text(t) => write!(f, "{}", t),
+            NodeType::Interpolation => write!(f, "interpolation"),
             NodeType::Ident(t, _) => write!(f, "Ident:{}", t),
             NodeType::Array(t) => write!(f, "Array:{}", t),
+            NodeType::ElementIndex => write!(f, "Index"),
+            NodeType::FieldAccess(name) => write!(f, "Field:{}", name),
+            NodeType::StructLiteral(literal) => write!(f, "{}-literal", literal.name),
+            NodeType::Variant(variant) => write!(f, "{}.{}", variant.enum_name, variant.name),
+            NodeType::Range(false) => write!(f, "range"),
+            NodeType::Range(true) => write!(f, "inclusive-range"),
             NodeType::UnaryOp(UnOp::Neg) => write!(f, "neg"),
             NodeType::UnaryOp(UnOp::Not) => write!(f, "not"),
+            NodeType::UnaryOp(UnOp::BitNot) => write!(f, "bnot"),
             NodeType::BinaryOp(BinOp::Add) => write!(f, "add"),
             NodeType::BinaryOp(BinOp::Sub) => write!(f, "sub"),
             NodeType::BinaryOp(BinOp::Mul) => write!(f, "mul"),
             NodeType::BinaryOp(BinOp::Div) => write!(f, "div"),
+            NodeType::BinaryOp(BinOp::IntDiv) => write!(f, "idiv"),
+            NodeType::BinaryOp(BinOp::Mod) => write!(f, "mod"),
             NodeType::BinaryOp(BinOp::Pow) => write!(f, "pow"),
+            NodeType::BinaryOp(BinOp::BitAnd) => write!(f, "band"),
+            NodeType::BinaryOp(BinOp::BitOr) => write!(f, "bor"),
+            NodeType::BinaryOp(BinOp::BitXor) => write!(f, "bxor"),
+            NodeType::BinaryOp(BinOp::Shl) => write!(f, "shl"),
+            NodeType::BinaryOp(BinOp::Shr) => write!(f, "shr"),
+            NodeType::BinaryOp(BinOp::Eq) => write!(f, "eq"),
+            NodeType::BinaryOp(BinOp::Ne) => write!(f, "ne"),
+            NodeType::BinaryOp(BinOp::Lt) => write!(f, "lt"),
+            NodeType::BinaryOp(BinOp::Le) => write!(f, "le"),
+            NodeType::BinaryOp(BinOp::Gt) => write!(f, "gt"),
+            NodeType::BinaryOp(BinOp::Ge) => write!(f, "ge"),
             NodeType::BinaryOp(BinOp::And) => write!(f, "and"),
             NodeType::BinaryOp(BinOp::Or) => write!(f, "or"),
-            NodeType::Function(_) => write!(f, "function"),
+            NodeType::Function(def) => match &def.name {
+                Some(name) => write!(f, "function:{}", name),
+                None => write!(f, "function"),
+            },
+            NodeType::Call => write!(f, "call"),
             NodeType::Assignment => write!(f, "assignment"),
+            NodeType::CompoundAssignment(op) => write!(f, "{}-assignment", op),
+            NodeType::Block => write!(f, "block"),
+            NodeType::If => write!(f, "if"),
             NodeType::Let => write!(f, "let"),
+            NodeType::Struct(name) => write!(f, "struct:{}", name),
+            NodeType::Enum(name) => write!(f, "enum:{}", name),
             NodeType::Print => write!(f, "print"),
+            NodeType::Return => write!(f, "return"),
+            NodeType::While(_) => write!(f, "while"),
+            NodeType::For(_) => write!(f, "for"),
+            NodeType::Loop(_) => write!(f, "loop"),
+            NodeType::Break(_) => write!(f, "break"),
+            NodeType::Continue(_) => write!(f, "continue"),
         }
     }
 }
@@ -95,6 +253,11 @@ pub struct Node {
     pub token: Option<Token>,
     // This gets filled in a subsequent pass
     pub return_type: BaseType,
+    // The type written for a variable, a parameter or a function's result, which
+    // later passes check the values against
+    pub annotation: Option<TypeAnnotation>,
+    // Doc comments written just before a declaration
+    pub doc: Option<String>,
 }
 
 impl Display for Node {
@@ -110,6 +273,16 @@ impl Node {
             children: vec![],
             token,
             return_type: Undefined,
+            annotation: None,
+            doc: None,
+        }
+    }
+
+    /// Create a node whose type is already known
+    pub fn typed(node_type: NodeType, token: Option<Token>, return_type: BaseType) -> Self {
+        Self {
+            return_type,
+            ..Self::new(node_type, token)
         }
     }
 
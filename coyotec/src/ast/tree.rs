@@ -23,12 +23,6 @@ pub enum ValueType {
     List,
 }
 
-impl ValueType {
-    pub(crate) fn text(text: Box<String>) -> ValueType {
-        todo!()
-    }
-}
-
 impl Display for ValueType {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
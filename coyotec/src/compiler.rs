@@ -1,8 +1,9 @@
 #![allow(unused_assignments, unused_variables)]
 use crate::generator::generate;
-use crate::lexer::{lex, SourceType};
-use crate::parse::parser::parse;
-use anyhow::{bail, Result};
+use crate::lexer::{lex_stream, LexMode, SourceType};
+use crate::parse::parser::parse_stream;
+use crate::sourcemap::SourceMap;
+use anyhow::Result;
 use cyasm::assembler::assemble;
 
 /// The compiler module is the entry point for the compiler. It takes a string of code
@@ -11,19 +12,19 @@ pub fn compile(code: &str, source_type: SourceType) -> Result<Vec<u8>> {
     println!("{code}");
     // Empty vector to hold the compiled bytecode
     let mut bytecode = Vec::new();
-    let tokens = lex(code, source_type)?;
+    let mut sources = SourceMap::new();
+    let file_id = sources.add(source_type.clone(), code.to_string());
+    let report = |err: &anyhow::Error| println!("{}", sources.render_error(err));
 
-    // Parse the tokens
-    if let Ok(node) = parse(tokens, code.to_string()) {
-        // Generate the assembly code
-        let asm = generate(&node);
-        println!("{}", asm);
-        // Assemble the assembly code into bytecode
-        bytecode = assemble(&asm);
-        println!("Bytecode: {:?}", bytecode);
-    } else {
-        bail!("Error parsing");
-    }
+    // Parse the tokens as they're lexed
+    let tokens = lex_stream(code, source_type, file_id, LexMode::Normal);
+    let node = parse_stream(tokens, code.to_string()).inspect_err(report)?;
+    // Generate the assembly code
+    let asm = generate(&node);
+    println!("{}", asm);
+    // Assemble the assembly code into bytecode
+    bytecode = assemble(&asm);
+    println!("Bytecode: {:?}", bytecode);
 
     Ok(bytecode)
 }
@@ -31,16 +32,646 @@ pub fn compile(code: &str, source_type: SourceType) -> Result<Vec<u8>> {
 #[cfg(test)]
 mod test {
     use crate::compiler::compile;
+    use crate::errors::{DiagnosticKind, Diagnostics};
+    use crate::generator::generate;
+    use crate::lexer::lex;
     use crate::lexer::SourceType;
+    use crate::parse::parser::parse;
+    use cvm::vm::{RuntimeError, Vm};
+
+    /// Compile and run `code`, returning everything it printed
+    fn run(code: &str) -> String {
+        try_run(code).expect("Runtime error")
+    }
+
+    /// Compile and run `code`, returning everything it printed or the error it stopped with
+    fn try_run(code: &str) -> Result<String, RuntimeError> {
+        let bytecode = compile(code, SourceType::Test).expect("Unable to compile");
+        let mut vm = Vm::with_output(Vec::new());
+        vm.code = bytecode;
+        vm.run()?;
+        Ok(String::from_utf8(vm.output().clone()).unwrap())
+    }
 
     #[test]
     fn test_compile() {
         let code = "1 * 2 + 3 * 4";
         println!("Testing compile: {}", code);
-        compile(code, SourceType::Interactive);
+        assert!(compile(code, SourceType::Interactive).is_ok());
 
         let code = "1 + 2 * 3 + 4";
         println!("Testing compile: {}", code);
-        compile(code, SourceType::Interactive);
+        assert!(compile(code, SourceType::Interactive).is_ok());
+
+        // Problems found by the parser come back as its diagnostics
+        let err = compile("print (1", SourceType::Test).unwrap_err();
+        let diagnostics = err.downcast_ref::<Diagnostics>().unwrap();
+        assert_eq!(diagnostics.0[0].kind, DiagnosticKind::InvalidSyntax);
+    }
+
+    #[test]
+    fn test_interpolation() {
+        assert_eq!(run(r#"print "total: ${1 + 2}!""#), "total: 3!\n");
+        assert_eq!(run(r#"print "${2 * 3}""#), "6\n");
+        assert_eq!(run(r#"print "a ${"b ${1}"} c""#), "a b 1 c\n");
+        assert_eq!(run(r#"print "cost: \${1}""#), "cost: ${1}\n");
+    }
+
+    #[test]
+    fn test_literal_keywords() {
+        assert_eq!(run("print true"), "true\n");
+        assert_eq!(run("print false"), "false\n");
+        assert_eq!(run("print nil"), "nil\n");
+    }
+
+    #[test]
+    fn test_chars() {
+        assert_eq!(run("print 'a'"), "a\n");
+        assert_eq!(run(r"print '\u{1F600}'"), "\u{1F600}\n");
+        assert_eq!(run(r"print '\''"), "'\n");
+        assert_eq!(run(r#"print "${'x'}y""#), "xy\n");
+    }
+
+    #[test]
+    fn test_variables() {
+        assert_eq!(run("let x = 2\nlet y = x * 3\nprint x + y"), "8\n");
+        assert_eq!(run("let x\nprint x"), "nil\n");
+    }
+
+    #[test]
+    fn test_assignment() {
+        assert_eq!(run("let x = 1\nx = x + 1\nprint x"), "2\n");
+        assert_eq!(
+            run("let x = 10\nx += 5\nx -= 3\nx *= 2\nx /= 4\nprint x"),
+            "6\n"
+        );
+        assert_eq!(
+            run("let a = [1, 2]\nlet i = 0\na[i] += 10\na[1] *= 3\nprint a"),
+            "[11, 6]\n"
+        );
+        assert_eq!(run("let s = \"a\"\ns = \"b\"\nprint s"), "b\n");
+        assert!(compile("let x = 1\nx + 1 = 2", SourceType::Test).is_err());
+    }
+
+    #[test]
+    fn test_arrays() {
+        assert_eq!(run(include_str!("../../code/test.cy")), "20\n");
+        assert_eq!(run("print [1, 2, 3]"), "[1, 2, 3]\n");
+        assert_eq!(run("print [[1], [2, 3]][1][0] + 1"), "3\n");
+        assert_eq!(run("let a = [\n  'a',\n  'b',\n]\nprint a[0]"), "a\n");
+        assert_eq!(
+            run("let x = [10, 20, 30]\nx[1] = 5\nx[2] = x[0] + x[1]\nprint x"),
+            "[10, 5, 15]\n"
+        );
+        assert_eq!(run("let t = [\"a\", \"b\"]\nprint t[1]"), "b\n");
+        assert_eq!(run("print -[1, 2][1]"), "-2\n");
+        assert!(compile("print [1, 2.5]", SourceType::Test).is_err());
+        // Element types only known when running are checked by the VM
+        assert!(try_run("func f(x) { x }\nprint [f(1), f(\"a\")]").is_err());
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let results = |code: &str| run(code).lines().collect::<Vec<_>>().join(" ");
+        assert_eq!(
+            results("print 1 < 2\nprint 2 <= 2\nprint 3 > 4\nprint 3 >= 4"),
+            "true true false false"
+        );
+        assert_eq!(
+            results("print 1 + 1 == 2\nprint 2 != 2.0\nprint 1.5 < 2\nprint 0.1 + 0.2 == 0.3"),
+            "true false true false"
+        );
+        assert_eq!(
+            results("print \"abc\" < \"abd\"\nprint \"a\" == \"a\"\nprint 'b' > 'a'"),
+            "true true true"
+        );
+        assert_eq!(
+            results("print true == false\nprint false < true\nprint nil == nil\nprint 1 != nil"),
+            "false true true true"
+        );
+        assert_eq!(results("let x = 5\nprint x * 2 > x + 4 == true"), "true");
+        // Values of different types are never equal, but can't be ordered
+        assert_eq!(
+            results("print 1 == \"a\"\nprint 'a' != true\nprint [1] == 1"),
+            "false true false"
+        );
+        assert!(try_run("func f(x) { x }\nprint f(1) < f(\"a\")").is_err());
+
+        // Arrays and structs are equal if what they hold is, and functions only equal
+        // themselves
+        assert_eq!(
+            results("let a = [[1, 2], [3]]\nprint a == a\nprint a == [[1, 2], [3]]\nprint [1, 2] != [1, 3]\nprint [1] == [1, 1]"),
+            "true true true false"
+        );
+        assert_eq!(
+            results("struct P { x: int, y: int }\nlet p = P { x: 1, y: 2 }\nprint p == p\nprint p == P { x: 1, y: 2 }\nprint p == P { x: 1, y: 3 }"),
+            "true true false"
+        );
+        assert_eq!(
+            results("func f() { 1 }\nfunc g() { 1 }\nlet h = f\nprint f == f\nprint f == h\nprint f == g"),
+            "true true false"
+        );
+    }
+
+    #[test]
+    fn test_logical_operators() {
+        let results = |code: &str| run(code).lines().collect::<Vec<_>>().join(" ");
+        assert_eq!(
+            results("print true and false\nprint false or true\nprint not true\nprint !false"),
+            "false true false true"
+        );
+        assert_eq!(
+            results("print 1 < 2 && 2 < 3 || false\nprint false and true or true"),
+            "true true"
+        );
+        // The right operand isn't evaluated when the left one decides the result, so
+        // the out of bounds index never runs
+        assert_eq!(
+            results("let a = [1]\nprint false and a[5] == 1\nprint true or a[5] == 1"),
+            "false true"
+        );
+    }
+
+    #[test]
+    fn test_not_needs_a_boolean() {
+        let err = try_run("print not 1").unwrap_err();
+        assert!(err.0.contains("`not` must be a boolean"), "{err}");
+    }
+
+    #[test]
+    fn test_integer_operators() {
+        let results = |code: &str| run(code).lines().collect::<Vec<_>>().join(" ");
+        assert_eq!(
+            results("print 7 % 3\nprint 7.5 % 2\nprint 2 ** 10\nprint 2 ** -1"),
+            "1 1.5 1024 0.5"
+        );
+        // The remainder goes with `~/`, so it takes the sign of the right operand
+        assert_eq!(
+            results("print -7 % 3\nprint 7 % -3\nprint -7 % -3\nprint -7.5 % 2\nprint 7.5 % -2"),
+            "2 -2 -1 0.5 -0.5"
+        );
+        assert_eq!(
+            results("let a = -7\nlet b = 3\nprint (a ~/ b) * b + a % b == a"),
+            "true"
+        );
+        assert_eq!(
+            results("print 7 ~/ 2\nprint -7 ~/ 2\nprint -7 / 2\nprint 7.5 ~/ 2"),
+            "3 -4 -3 3"
+        );
+        assert_eq!(
+            results("print 12 & 10\nprint 12 | 10\nprint 12 ^ 10\nprint ~0\nprint 1 << 4\nprint -16 >> 2"),
+            "8 14 6 -1 16 -4"
+        );
+        assert_eq!(results("print 1 | 2 == 3\nprint 2 + 3 * 4 % 5"), "true 4");
+        assert_eq!(results("let x = 17\nx %= 5\nprint x"), "2");
+    }
+
+    #[test]
+    fn test_bitwise_needs_integers() {
+        let err = try_run("print 1.5 & 1").unwrap_err();
+        assert!(err.0.contains("only works on integers"), "{err}");
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        for code in [
+            "let a = [1, 2]\nprint a[2]",
+            "let a = [1, 2]\na[-1] = 3",
+            "let a = [1, 2]\nfunc f(x) { x }\na[0] = f(\"x\")",
+            "func f(x) { x }\nprint 1 / f(0)",
+            "func f(x) { x }\nprint 1 % f(0)",
+            "func f(x) { x }\nprint 1 ~/ f(0)",
+            "print 9223372036854775807 + 1",
+            "print 2 ** 64",
+            "func f(x) { x }\nprint f(1) + f(\"a\")",
+            "struct P { x: int }\nfunc f(p) { p.y }\nprint f(P { x: 1 })",
+            "func f(c) { if c { 1 } }\nprint f(1)",
+            "func f(c) { while c { } }\nprint f(\"a\")",
+            "func f(x) { x }\nprint -f(\"a\")",
+            "func f(x) { x }\nprint f(1)..f(2.5)",
+        ] {
+            assert!(try_run(code).is_err(), "{code}");
+        }
+    }
+
+    #[test]
+    fn test_if() {
+        let code = "
+            let x = 15
+            if x % 15 == 0 {
+                print \"fizzbuzz\"
+            } else if x % 3 == 0 {
+                print \"fizz\"
+            } else {
+                print x
+            }
+            if x > 100 { print \"big\" }
+            print \"done\"
+        ";
+        assert_eq!(run(code), "fizzbuzz\ndone\n");
+
+        let code = "
+            let n = 7
+            let kind = if n % 2 == 0 { \"even\" } else { \"odd\" }
+            print kind
+            print if n > 5 { let d = n - 5; d * 10 } else { 0 }
+            print if false { 1 }
+        ";
+        assert_eq!(run(code), "odd\n20\nnil\n");
+        assert!(compile("if 1 { print 2 }", SourceType::Test).is_err());
+    }
+
+    #[test]
+    fn test_loops() {
+        let code = "
+            let i = 0
+            let total = 0
+            while i < 10 {
+                i += 1
+                if i % 2 == 0 { continue }
+                total += i
+            }
+            print total
+            loop {
+                i -= 3
+                if i < 0 { break }
+            }
+            print i
+        ";
+        assert_eq!(run(code), "25\n-2\n");
+
+        let code = "
+            let found = 0
+            let x = 0
+            'outer: while x < 5 {
+                x += 1
+                let y = 0
+                loop {
+                    y += 1
+                    if y > x { continue 'outer }
+                    if x * y == 12 {
+                        found = x * 10 + y
+                        break 'outer
+                    }
+                }
+            }
+            print found
+        ";
+        assert_eq!(run(code), "43\n");
+
+        // Jumping out of the middle of an expression drops what it had on the stack
+        let code = "
+            let i = 0
+            let total = 0
+            while i < 130000 {
+                i += 1
+                total += (if i % 2 == 0 { continue } else { 1 }) + 1
+            }
+            print total
+            'outer: for x in 0..3 {
+                for y in 0..3 {
+                    print [x, if y > x { continue 'outer } else { y }]
+                }
+            }
+        ";
+        assert_eq!(
+            run(code),
+            "130000\n[0, 0]\n[1, 0]\n[1, 1]\n[2, 0]\n[2, 1]\n[2, 2]\n"
+        );
+
+        for code in ["break", "if true { continue }", "loop { break 'outer }"] {
+            assert!(compile(code, SourceType::Test).is_err());
+        }
+    }
+
+    #[test]
+    fn test_for() {
+        let code = "
+            let total = 0
+            for i in 1..=4 { total += i }
+            print total
+            for i in 5..2 { print i }
+            let names = [\"a\", \"b\", \"c\"]
+            for name in names {
+                if name == \"b\" { continue }
+                print name
+            }
+            let squares = 0..4
+            for x in squares { squares[x] = x * x }
+            print squares
+        ";
+        assert_eq!(run(code), "10\na\nc\n[0, 1, 4, 9]\n");
+
+        // The loop variable hides an outer one only inside the body
+        let code = "
+            let x = 100
+            'rows: for x in 0..3 {
+                for y in [10, 20] {
+                    if x == 2 { break 'rows }
+                    print x + y
+                }
+            }
+            print x
+        ";
+        assert_eq!(run(code), "10\n20\n11\n21\n100\n");
+
+        for code in [
+            "for x in 5 { }",
+            "for x in 1..2.5 { }",
+            "for x in \"ab\" { }",
+            "for i, x in [1, 2] { }",
+        ] {
+            assert!(compile(code, SourceType::Test).is_err());
+        }
+    }
+
+    #[test]
+    fn test_functions() {
+        let code = "
+            func add(a: int, b: int) -> int {
+                return a + b
+            }
+            func fact(n: int) -> int {
+                if n <= 1 { 1 } else { n * fact(n - 1) }
+            }
+            print add(2, 3)
+            print fact(10)
+            print add(fact(3), add(1, 1))
+        ";
+        assert_eq!(run(code), "5\n3628800\n8\n");
+
+        // Locals belong to each call, so recursion doesn't clobber them
+        let code = "
+            let total = 0
+            func sum(items) {
+                let subtotal = 0
+                for item in items {
+                    subtotal += item
+                }
+                total += subtotal
+                subtotal
+            }
+            func fib(n) {
+                if n < 2 { return n }
+                let a = fib(n - 1)
+                let b = fib(n - 2)
+                a + b
+            }
+            print sum([1, 2, 3])
+            print fib(15)
+            print total
+            func nothing() { return }
+            print nothing()
+        ";
+        assert_eq!(run(code), "6\n610\n6\nnil\n");
+
+        for code in [
+            "return 1",
+            "func f() { loop { } }\nbreak",
+            "while true { func f() { break } }",
+            "func f(a) { a }\nprint f(1, 2)",
+        ] {
+            assert!(compile(code, SourceType::Test).is_err());
+        }
+        // Functions can be called before they're declared, and use globals declared
+        // after them
+        let code = "
+            print is_even(10)
+            func is_even(n) { if n == 0 { true } else { is_odd(n - 1) } }
+            func is_odd(n) { if n == 0 { false } else { is_even(n - 1) } }
+            func scaled(x) { x * factor }
+            let factor = 3
+            print scaled(2)
+        ";
+        assert_eq!(run(code), "true\n6\n");
+
+        // Calls through variables are checked when they run
+        assert!(try_run("let f = func(a) { a }\nprint f(1, 2)").is_err());
+        assert!(try_run("let f = 1\nprint f()").is_err());
+    }
+
+    #[test]
+    fn test_annotations() {
+        // Integers become floats where a float was declared
+        let code = "
+            let x: float = 1
+            print x / 2
+            x = 3
+            print x / 2
+            func half(n: float) -> float { n / 2 }
+            print half(5)
+            func whole() -> float { return 4 }
+            print whole() / 8
+            let n: int = 7
+            print n / 2
+        ";
+        assert_eq!(run(code), "0.5\n1.5\n2.5\n0.5\n3\n");
+        assert_eq!(run("let x: float = 1\nx += 1\nprint x / 4"), "0.5\n");
+        // So do the elements of arrays declared to hold floats
+        assert_eq!(
+            run("let a: [float] = [1, 2]\na[0] = 1.5\na[1] = 3\nprint a[0] + a[1] / 2"),
+            "3\n"
+        );
+        assert_eq!(
+            run("func f() -> [[float]] { [[1]] }\nlet a = f()\na[0][0] = 0.5\nprint a"),
+            "[[0.5]]\n"
+        );
+        assert!(compile("let x: int = 1\nx += 1.5", SourceType::Test).is_err());
+
+        // Only values that may be integers are converted, and ones whose type isn't
+        // known until they're used are checked then
+        let asm = |code: &str| {
+            let tokens = lex(code, SourceType::Test).unwrap();
+            generate(&parse(tokens, code.to_string()).unwrap())
+        };
+        assert!(asm("let x: float = 1").contains("tofloat"));
+        assert!(!asm("let x: float = 1.5").contains("tofloat"));
+        assert_eq!(run("let f = func(x: float) { x / 2 }\nprint f(3)"), "1.5\n");
+        assert!(try_run("let f = func(x: float) { x }\nprint f(\"a\")").is_err());
+    }
+
+    #[test]
+    fn test_structs() {
+        let code = "
+            struct Point { x: float, y: float }
+            struct Line { start: Point, end: Point }
+            func length(line: Line) -> float {
+                let dx = line.end.x - line.start.x
+                let dy = line.end.y - line.start.y
+                (dx * dx + dy * dy) ** 0.5
+            }
+            let line = Line { start: Point { x: 0, y: 0 }, end: Point { x: 3, y: 4 } }
+            print length(line)
+            line.end.y = 0
+            line.start.x += 1
+            print line
+            print line.end.x / 2
+            let p = line.start
+            if p.x > 0 { print \"right\" }
+        ";
+        assert_eq!(
+            run(code),
+            "5\nLine { start: Point { x: 1, y: 0 }, end: Point { x: 3, y: 0 } }\n1.5\nright\n"
+        );
+    }
+
+    #[test]
+    fn test_enums() {
+        let code = "
+            enum Shape { Circle(float), Rect(float, float), Empty }
+            struct Drawing { shape: Shape }
+            let shapes = [Shape.Circle(1), Shape.Rect(2, 3.5), Shape.Empty]
+            for shape in shapes { print shape }
+            let d = Drawing { shape: Shape.Empty }
+            print d.shape == Shape.Empty
+            print shapes[0] == Shape.Circle(1.0)
+            print shapes[0] != Shape.Circle(2)
+            print shapes[1] == Shape.Empty
+        ";
+        assert_eq!(
+            run(code),
+            "Shape.Circle(1)\nShape.Rect(2, 3.5)\nShape.Empty\ntrue\ntrue\ntrue\nfalse\n"
+        );
+    }
+
+    #[test]
+    fn test_closures() {
+        let code = "
+            func make_counter(step) {
+                let count = 0
+                func() {
+                    count += step
+                    count
+                }
+            }
+            let a = make_counter(1)
+            let b = make_counter(10)
+            a()
+            a()
+            b()
+            print a()
+            print b()
+        ";
+        assert_eq!(run(code), "3\n20\n");
+
+        // Closures made in the same call share what they capture, and functions
+        // can be passed in and handed on through several levels
+        let code = "
+            func apply(f, x) { f(x) }
+            func pair() {
+                let value = 1
+                let get = func() { value }
+                let set = func(v) { value = v }
+                [get, set]
+            }
+            func adder(a) {
+                func(b) {
+                    func(c) { a + b + c }
+                }
+            }
+            let fns = pair()
+            fns[1](42)
+            print fns[0]()
+            print apply(adder(1)(2), 3)
+            let factor = 3
+            print apply(func(x) { x * factor }, 5)
+        ";
+        assert_eq!(run(code), "42\n6\n15\n");
+
+        // A nested function can call itself through the variable it's stored in
+        let code = "
+            func countdown(n) {
+                func step(i) {
+                    if i > 0 { step(i - 1) } else { n }
+                }
+                step(n)
+            }
+            print countdown(4)
+        ";
+        assert_eq!(run(code), "4\n");
+    }
+
+    #[test]
+    fn test_scopes() {
+        let code = "
+            let x = 1
+            {
+                let x = x + 10
+                print x
+                {
+                    let x = \"inner\"
+                    print x
+                }
+                print x
+            }
+            print x
+            let y = { let t = 5; t * 2 }
+            print y
+        ";
+        assert_eq!(run(code), "11\ninner\n11\n1\n10\n");
+        // A block's variables can't be used after it ends
+        assert!(compile("{ let t = 1 }\nprint t", SourceType::Test).is_err());
+
+        // Each pass of a loop has its own variables, and jumping out of a scope
+        // leaves closures with the values they captured
+        let code = "
+            func collect() {
+                let fns = [func() { 0 }, func() { 0 }, func() { 0 }]
+                for i in 0..3 {
+                    let doubled = i * 2
+                    fns[i] = func() { doubled + i }
+                }
+                fns
+            }
+            func last() {
+                let f = func() { 0 }
+                for i in 0..10 {
+                    let j = i
+                    f = func() { j }
+                    if i == 4 { break }
+                }
+                let reused = 100
+                f()
+            }
+            let fns = collect()
+            print fns[0]()
+            print fns[2]()
+            print last()
+        ";
+        assert_eq!(run(code), "0\n6\n4\n");
+
+        // The same holds outside of functions
+        let code = "
+            let fns = [func() { 0 }, func() { 0 }, func() { 0 }]
+            for i in 0..3 {
+                let d = i
+                fns[i] = func() { d }
+            }
+            print fns[0]()
+            print fns[1]()
+            let f = func() { 0 }
+            for i in 0..10 {
+                let j = i * 10
+                f = func() { j }
+                if i == 4 { break }
+            }
+            {
+                func countdown(n) { if n > 0 { countdown(n - 1) } else { f() } }
+                print countdown(3)
+            }
+        ";
+        assert_eq!(run(code), "0\n1\n40\n");
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert_eq!(run("print 9007199254740993 + 0"), "9007199254740993\n");
+        assert_eq!(run("print 0xFF + 0b1010 + 0o17"), "280\n");
+        assert_eq!(run("print 7 / 2"), "3\n");
+        assert_eq!(run("print 7 / 2.0"), "3.5\n");
+        assert_eq!(run("print 1.5e-3 * 2"), "0.003\n");
+        assert_eq!(run("print -(2 + 3)"), "-5\n");
+        assert_eq!(run("print 2f"), "2\n");
+        assert!(compile("print 99999999999999999999", SourceType::Test).is_err());
     }
 }
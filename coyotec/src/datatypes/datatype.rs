@@ -1,3 +1,6 @@
+use crate::tokens::BaseType;
+use std::fmt::Display;
+
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum NativeType {}
 
@@ -6,11 +9,14 @@ pub enum DataType {
     Integer,
     Float,
     Boolean,
+    Char,
     Text,
     Array,
-    Function,
+    // A function declared with `func`, and the index of its signature in the registry
+    Function(usize),
     List,
     Struct(usize),
+    Enum(usize),
     None,
 }
 
@@ -20,11 +26,13 @@ impl DataType {
             DataType::Integer => "i",
             DataType::Float => "f",
             DataType::Boolean => "b",
+            DataType::Char => "c",
             DataType::Text => "s",
             DataType::Array => "a",
-            DataType::Function => "f",
+            DataType::Function(_) => "f",
             DataType::List => "l",
             DataType::Struct(_) => "s",
+            DataType::Enum(_) => "e",
             DataType::None => "n",
         }
     }
@@ -34,12 +42,82 @@ impl DataType {
             DataType::Integer => 6,
             DataType::Float => 1,
             DataType::Boolean => 2,
+            DataType::Char => 4,
             DataType::Text => 3,
             DataType::Array => 3,
-            DataType::Function => 3,
+            DataType::Function(_) => 3,
             DataType::List => 3,
             DataType::Struct(_) => 3,
+            DataType::Enum(_) => 3,
             DataType::None => 0,
         }
     }
 }
+
+impl DataType {
+    /// The type the parser gives expressions that use a variable of this type
+    pub fn base_type(&self) -> BaseType {
+        match self {
+            DataType::Integer => BaseType::Integer,
+            DataType::Float => BaseType::Float,
+            DataType::Boolean => BaseType::Boolean,
+            DataType::Char => BaseType::Char,
+            DataType::Text => BaseType::Text,
+            DataType::Array => BaseType::Array,
+            DataType::List => BaseType::List,
+            DataType::Struct(_) => BaseType::Struct,
+            DataType::Enum(_) => BaseType::Enum,
+            DataType::Function(_) | DataType::None => BaseType::Undefined,
+        }
+    }
+}
+
+/// A type as it's written in the code, such as `int`, `[int]` or the name of a struct
+#[derive(Clone, PartialEq, Debug)]
+pub enum TypeAnnotation {
+    Base(BaseType),
+    Array(Box<TypeAnnotation>),
+    // A user defined type, which is looked up in the type registry
+    Named(String),
+}
+
+impl TypeAnnotation {
+    /// The type of the values this annotation allows, as far as the parser tracks it
+    pub fn base_type(&self) -> BaseType {
+        match self {
+            TypeAnnotation::Base(base_type) => base_type.clone(),
+            TypeAnnotation::Array(_) => BaseType::Array,
+            // What a name stands for is up to the type registry
+            TypeAnnotation::Named(_) => BaseType::Undefined,
+        }
+    }
+}
+
+impl From<&TypeAnnotation> for DataType {
+    fn from(annotation: &TypeAnnotation) -> Self {
+        match annotation.base_type() {
+            BaseType::Integer => DataType::Integer,
+            BaseType::Float => DataType::Float,
+            BaseType::Boolean => DataType::Boolean,
+            BaseType::Char => DataType::Char,
+            BaseType::Text => DataType::Text,
+            BaseType::Array => DataType::Array,
+            _ => DataType::None,
+        }
+    }
+}
+
+impl Display for TypeAnnotation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TypeAnnotation::Base(BaseType::Integer) => write!(f, "int"),
+            TypeAnnotation::Base(BaseType::Float) => write!(f, "float"),
+            TypeAnnotation::Base(BaseType::Text) => write!(f, "text"),
+            TypeAnnotation::Base(BaseType::Boolean) => write!(f, "bool"),
+            TypeAnnotation::Base(BaseType::Char) => write!(f, "char"),
+            TypeAnnotation::Base(base_type) => write!(f, "{base_type}"),
+            TypeAnnotation::Array(element) => write!(f, "[{element}]"),
+            TypeAnnotation::Named(name) => write!(f, "{name}"),
+        }
+    }
+}
@@ -1 +1,2 @@
 pub mod datatype;
+pub mod registry;
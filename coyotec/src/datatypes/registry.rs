@@ -0,0 +1,195 @@
+use crate::datatypes::datatype::{DataType, TypeAnnotation};
+use crate::tokens::BaseType;
+use std::collections::HashMap;
+
+/// A struct declared in the code, with its fields in the order they were declared
+#[derive(Clone, Debug, PartialEq)]
+pub struct StructType {
+    pub name: String,
+    pub fields: Vec<(String, TypeAnnotation)>,
+}
+
+impl StructType {
+    /// The position and type of the field called `name`
+    pub fn field(&self, name: &str) -> Option<(usize, &TypeAnnotation)> {
+        self.fields
+            .iter()
+            .position(|(field, _)| field == name)
+            .map(|i| (i, &self.fields[i].1))
+    }
+}
+
+/// An enum declared in the code. Each variant has the types of the values it carries,
+/// and its position is the tag that its values have at runtime.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnumType {
+    pub name: String,
+    pub variants: Vec<(String, Vec<TypeAnnotation>)>,
+}
+
+impl EnumType {
+    /// The tag and payload types of the variant called `name`
+    pub fn variant(&self, name: &str) -> Option<(usize, &[TypeAnnotation])> {
+        self.variants
+            .iter()
+            .position(|(variant, _)| variant == name)
+            .map(|i| (i, self.variants[i].1.as_slice()))
+    }
+}
+
+/// The parameter types of a function declared with `func`, for those that were
+/// given one
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionType {
+    pub params: Vec<Option<TypeAnnotation>>,
+}
+
+/// The types declared in the code. A struct's index here is the one held by
+/// `DataType::Struct`, an enum's is the one held by `DataType::Enum`, and a function's
+/// is the one held by `DataType::Function`.
+#[derive(Clone, Debug, Default)]
+pub struct TypeRegistry {
+    structs: Vec<StructType>,
+    enums: Vec<EnumType>,
+    functions: Vec<FunctionType>,
+    names: HashMap<String, DataType>,
+}
+
+impl TypeRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a struct and return its index. Declaring a name again replaces the type it
+    /// refers to from then on.
+    pub fn add_struct(&mut self, struct_type: StructType) -> usize {
+        let index = self.structs.len();
+        let data_type = DataType::Struct(index);
+        self.names.insert(struct_type.name.clone(), data_type);
+        self.structs.push(struct_type);
+        index
+    }
+
+    /// Add an enum and return its index
+    pub fn add_enum(&mut self, enum_type: EnumType) -> usize {
+        let index = self.enums.len();
+        let data_type = DataType::Enum(index);
+        self.names.insert(enum_type.name.clone(), data_type);
+        self.enums.push(enum_type);
+        index
+    }
+
+    /// Add the signature of a function and return its index. Functions aren't types,
+    /// so its name isn't recorded.
+    pub fn add_function(&mut self, function_type: FunctionType) -> usize {
+        self.functions.push(function_type);
+        self.functions.len() - 1
+    }
+
+    pub fn get_function(&self, index: usize) -> &FunctionType {
+        &self.functions[index]
+    }
+
+    pub fn get_struct(&self, index: usize) -> &StructType {
+        &self.structs[index]
+    }
+
+    pub fn get_struct_mut(&mut self, index: usize) -> &mut StructType {
+        &mut self.structs[index]
+    }
+
+    pub fn get_enum(&self, index: usize) -> &EnumType {
+        &self.enums[index]
+    }
+
+    pub fn get_enum_mut(&mut self, index: usize) -> &mut EnumType {
+        &mut self.enums[index]
+    }
+
+    /// The type declared with the name `name`
+    pub fn find(&self, name: &str) -> Option<DataType> {
+        self.names.get(name).copied()
+    }
+
+    /// The index of the struct called `name`
+    pub fn find_struct(&self, name: &str) -> Option<usize> {
+        match self.find(name) {
+            Some(DataType::Struct(index)) => Some(index),
+            _ => None,
+        }
+    }
+
+    /// The index of the enum called `name`
+    pub fn find_enum(&self, name: &str) -> Option<usize> {
+        match self.find(name) {
+            Some(DataType::Enum(index)) => Some(index),
+            _ => None,
+        }
+    }
+
+    /// The name of the struct or enum that `data_type` is
+    pub fn name_of(&self, data_type: DataType) -> Option<&str> {
+        match data_type {
+            DataType::Struct(index) => Some(&self.structs[index].name),
+            DataType::Enum(index) => Some(&self.enums[index].name),
+            _ => None,
+        }
+    }
+
+    /// The type of values that an annotation allows, with the names of user types
+    /// looked up
+    pub fn resolve(&self, annotation: &TypeAnnotation) -> DataType {
+        match annotation {
+            TypeAnnotation::Named(name) => self.find(name).unwrap_or(DataType::None),
+            _ => DataType::from(annotation),
+        }
+    }
+
+    /// The type the parser gives values that fit an annotation
+    pub fn base_type(&self, annotation: &TypeAnnotation) -> BaseType {
+        self.resolve(annotation).base_type()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_structs() {
+        let mut registry = TypeRegistry::new();
+        let float = TypeAnnotation::Base(BaseType::Float);
+        let point = registry.add_struct(StructType {
+            name: "Point".to_string(),
+            fields: vec![("x".to_string(), float.clone()), ("y".to_string(), float)],
+        });
+        assert_eq!(registry.find_struct("Point"), Some(point));
+        assert_eq!(registry.get_struct(point).field("y").unwrap().0, 1);
+        assert!(registry.get_struct(point).field("z").is_none());
+        let named = TypeAnnotation::Named("Point".to_string());
+        assert_eq!(registry.resolve(&named), DataType::Struct(point));
+        let unknown = TypeAnnotation::Named("Line".to_string());
+        assert_eq!(registry.resolve(&unknown), DataType::None);
+    }
+
+    #[test]
+    fn test_enums() {
+        let mut registry = TypeRegistry::new();
+        let float = TypeAnnotation::Base(BaseType::Float);
+        let shape = registry.add_enum(EnumType {
+            name: "Shape".to_string(),
+            variants: vec![
+                ("Circle".to_string(), vec![float.clone()]),
+                ("Rect".to_string(), vec![float.clone(), float]),
+                ("Empty".to_string(), vec![]),
+            ],
+        });
+        assert_eq!(registry.find_enum("Shape"), Some(shape));
+        assert_eq!(registry.find_struct("Shape"), None);
+        let (tag, payload) = registry.get_enum(shape).variant("Rect").unwrap();
+        assert_eq!((tag, payload.len()), (1, 2));
+        assert!(registry.get_enum(shape).variant("Square").is_none());
+        let named = TypeAnnotation::Named("Shape".to_string());
+        assert_eq!(registry.base_type(&named), BaseType::Enum);
+    }
+}
@@ -2,7 +2,7 @@
 use crate::ast::tree::*;
 /// Display the ast
 pub fn display_ast(node: &Node, level: usize) {
-    println!("{}", node);
+    println!("{}{}", "  ".repeat(level), node);
     for child in &node.children {
         display_ast(child, level + 1);
     }
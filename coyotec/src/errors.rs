@@ -1 +1,64 @@
+//! Structured diagnostics for problems found while compiling
+use crate::tokens::{Location, Span};
+use std::fmt::{Display, Formatter};
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiagnosticKind {
+    UnexpectedCharacter,
+    UnterminatedString,
+    UnterminatedComment,
+    InvalidNumber,
+    InvalidEscape,
+    InvalidString,
+    InvalidChar,
+    InvalidAssignment,
+    TypeMismatch,
+    InvalidBreak,
+    InvalidReturn,
+    UnknownType,
+    InvalidField,
+    InvalidVariant,
+    InvalidSyntax,
+    InvalidCall,
+    UnknownVariable,
+}
+
+/// A single problem in the source, along with where it was found
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub message: String,
+    // Where the problem starts
+    pub location: Location,
+    pub span: Span,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} at line {} position {}",
+            self.message,
+            self.location.line,
+            self.location.column + 1
+        )
+    }
+}
+
+/// Every diagnostic from a stage, returned as one error
+#[derive(Debug)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl Display for Diagnostics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, diagnostic) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{diagnostic}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostics {}
@@ -2,25 +2,77 @@
 #![allow(dead_code, unused_variables)]
 
 use crate::allocator::Registers;
-use crate::ast::node::{BinOp, NodeType, UnOp};
-use crate::ast::tree::{Command, Node, ValueType};
-use crate::datatypes::datatype::DataType;
+use crate::ast::node::{BinOp, FunctionDef, NodeType, UnOp};
+use crate::ast::tree::Node;
+use crate::datatypes::datatype::TypeAnnotation;
 use crate::tokens::{BaseType, TokenType};
-use anyhow::anyhow;
-use std::collections::HashMap;
-use std::error::Error;
+use std::collections::HashSet;
 use std::fmt::{Display, Formatter};
 
 /// These are the instructions that the IR will have
 /// The IR will be in SSA form
-
 pub struct IrGenerator {
     registers: Registers,
     instructions: Vec<String>,
     string_pool: Vec<String>,
     strings_index: usize,
     scope: usize,
-    symbol_regs: Vec<HashMap<String, usize>>,
+    // Where variables outside of functions are kept, by block scope
+    symbol_regs: Vec<Scope>,
+    // Variables in blocks outside of functions are kept in locals, like those of a
+    // function, so each pass through a loop gets its own for closures to capture
+    locals: Registers,
+    // Those locals that closures have captured
+    captured: HashSet<usize>,
+    // Used to give every label a unique name
+    label_count: usize,
+    // The loops around the code being generated, innermost last
+    loops: Vec<LoopLabels>,
+    // How many values that expressions are still to use are on the stack in the
+    // function being generated
+    temporaries: usize,
+    // The functions around the code being generated, innermost last
+    functions: Vec<FunctionScope>,
+}
+
+/// Where a variable lives: in a global register, in a local of the function being
+/// generated, or in one of the upvalues the function captured
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Slot {
+    Register(usize),
+    Local(usize),
+    Upvalue(usize),
+}
+
+/// The most locals a single call can use
+const MAX_LOCALS: usize = 4096;
+
+/// The variables declared in a block and where they're kept, in the order they were
+/// declared. A later declaration of the same name shadows the earlier one.
+type Scope = Vec<(String, Slot)>;
+
+/// A function whose body is being generated
+struct FunctionScope {
+    locals: Registers,
+    // The local each variable of the function is kept in, by block scope
+    scopes: Vec<Scope>,
+    // The variables captured from the functions around it, by upvalue
+    upvalues: Vec<String>,
+    // Locals that closures have captured
+    captured: HashSet<usize>,
+    // The type the function was declared to return
+    returns: BaseType,
+}
+
+/// Where `continue` and `break` jump to for a loop
+struct LoopLabels {
+    name: Option<String>,
+    continue_label: String,
+    break_label: String,
+    // How many scopes of the function were open outside the loop's body
+    depth: usize,
+    // How many values were on the stack outside the loop
+    temporaries: usize,
 }
 
 pub fn generate(node: &Node) -> String {
@@ -34,7 +86,7 @@ impl Display for IrGenerator {
         // Write out the constants
         writeln!(f, ".constants")?;
         for s in self.string_pool.iter() {
-            writeln!(f, "    {}", s)?;
+            writeln!(f, "    {:?}", s)?;
         }
         writeln!(f, ".end")?;
         //writeln!(f, ".instructions")?;
@@ -42,7 +94,7 @@ impl Display for IrGenerator {
             writeln!(f, "{line}")?;
         }
         //writeln!(f, ".end")?;
-        writeln!(f, "")
+        writeln!(f)
     }
 }
 
@@ -54,7 +106,13 @@ impl IrGenerator {
             string_pool: Vec::new(),
             strings_index: 0,
             scope: 0,
-            symbol_regs: vec![HashMap::new()],
+            symbol_regs: vec![Scope::new()],
+            locals: Registers::new(MAX_LOCALS),
+            captured: HashSet::new(),
+            label_count: 0,
+            loops: Vec::new(),
+            temporaries: 0,
+            functions: Vec::new(),
         }
     }
 
@@ -77,14 +135,630 @@ impl IrGenerator {
         idx
     }
 
-    fn store_variable(&mut self, name: &str) -> usize {
-        let reg = self.registers.allocate();
-        self.symbol_regs[self.scope].insert(name.to_string(), reg);
-        reg
+    /// Find somewhere to keep a value: a local in a function or a block, or a
+    /// register at the top level
+    fn allocate(&mut self) -> Slot {
+        match self.functions.last_mut() {
+            Some(function) => Slot::Local(function.locals.allocate()),
+            None if self.scope > 0 => Slot::Local(self.locals.allocate()),
+            None => Slot::Register(self.registers.allocate()),
+        }
+    }
+
+    fn free(&mut self, slot: Slot) {
+        match slot {
+            Slot::Local(n) => match self.functions.last_mut() {
+                Some(function) => function.locals.free_register(n),
+                None => self.locals.free_register(n),
+            },
+            Slot::Register(reg) => self.registers.free_register(reg),
+            Slot::Upvalue(_) => {}
+        }
+    }
+
+    /// Push the value in `slot`
+    fn load(&mut self, slot: Slot) {
+        match slot {
+            Slot::Register(reg) => self.push(format!("load {reg} ;")),
+            Slot::Local(n) => self.push(format!("get_local {n} ;")),
+            Slot::Upvalue(n) => self.push(format!("get_upvalue {n} ;")),
+        }
+    }
+
+    /// Pop the top of the stack into `slot`
+    fn store(&mut self, slot: Slot) {
+        match slot {
+            Slot::Register(reg) => self.push(format!("store {reg} ;")),
+            Slot::Local(n) => self.push(format!("set_local {n} ;")),
+            Slot::Upvalue(n) => self.push(format!("set_upvalue {n} ;")),
+        }
+    }
+
+    /// Give every global that `root` declares a register, so that they can be used
+    /// before they're declared
+    fn declare_globals(&mut self, root: &Node) {
+        for child in &root.children {
+            let name = match &child.node_type {
+                NodeType::Let => match &child.children[0].node_type {
+                    NodeType::Ident(name, _) => name.as_str(),
+                    _ => continue,
+                },
+                NodeType::Function(def) => match &def.name {
+                    Some(name) => name.as_str(),
+                    None => continue,
+                },
+                _ => continue,
+            };
+            if !self.symbol_regs[0].iter().any(|(n, _)| n == name) {
+                let slot = Slot::Register(self.registers.allocate());
+                self.symbol_regs[0].push((name.to_string(), slot));
+            }
+        }
+    }
+
+    /// Keep a new variable in a fresh slot, shadowing any variable with the same name.
+    /// Each global has one register, which declaring it again reuses.
+    fn store_variable(&mut self, name: &str) -> Slot {
+        if self.functions.is_empty() && self.scope == 0 {
+            if let Some(&(_, slot)) = self.symbol_regs[0].iter().find(|(n, _)| n == name) {
+                return slot;
+            }
+        }
+        let slot = self.allocate();
+        match self.functions.last_mut() {
+            Some(function) => function.scopes.last_mut(),
+            None => self.symbol_regs.last_mut(),
+        }
+        .expect("There's always a scope to declare variables in")
+        .push((name.to_string(), slot));
+        slot
     }
 
-    fn get_variable(&mut self, name: &str) -> Option<usize> {
-        self.symbol_regs[self.scope].get(name).copied()
+    /// Look a variable up in the scopes of the function being generated, innermost
+    /// first, then in what it captured, then in the scopes outside of functions
+    fn get_variable(&mut self, name: &str) -> Option<Slot> {
+        fn find(scopes: &[Scope], name: &str) -> Option<Slot> {
+            scopes
+                .iter()
+                .rev()
+                .find_map(|scope| scope.iter().rev().find(|(n, _)| n == name))
+                .map(|&(_, slot)| slot)
+        }
+
+        if let Some(function) = self.functions.last() {
+            if let Some(slot) = find(&function.scopes, name) {
+                return Some(slot);
+            }
+            if let Some(n) = function.upvalues.iter().position(|u| u == name) {
+                return Some(Slot::Upvalue(n));
+            }
+            // Functions capture the variables of blocks, so only globals are left
+            return find(&self.symbol_regs[..1], name);
+        }
+        find(&self.symbol_regs, name)
+    }
+
+    /// Open a scope for the variables of a block
+    fn push_scope(&mut self) {
+        match self.functions.last_mut() {
+            Some(function) => function.scopes.push(Scope::new()),
+            None => {
+                self.symbol_regs.push(Scope::new());
+                self.scope += 1;
+            }
+        }
+    }
+
+    /// Close the innermost scope and reclaim the storage of its variables. Any
+    /// closure that captured one of them keeps its last value.
+    fn pop_scope(&mut self) {
+        let (scope, locals, captured) = match self.functions.last_mut() {
+            Some(function) => (
+                function.scopes.pop(),
+                &mut function.locals,
+                &mut function.captured,
+            ),
+            None => {
+                self.scope -= 1;
+                (self.symbol_regs.pop(), &mut self.locals, &mut self.captured)
+            }
+        };
+        let mut closes = Vec::new();
+        for (_, slot) in scope.expect("No scope to pop") {
+            match slot {
+                Slot::Local(n) => {
+                    if captured.remove(&n) {
+                        closes.push(format!("close_upvalue {n} ;"));
+                    }
+                    locals.free_register(n);
+                }
+                Slot::Register(reg) => self.registers.free_register(reg),
+                Slot::Upvalue(_) => {}
+            }
+        }
+        self.instructions.extend(closes);
+    }
+
+    /// Close the upvalues of every local in the scopes of the function that are
+    /// deeper than `depth`, for jumping out of them. Closures made later on in
+    /// those scopes aren't known yet, so every local is closed.
+    fn close_scopes(&mut self, depth: usize) {
+        let scopes = match self.functions.last() {
+            Some(function) => &function.scopes,
+            None => &self.symbol_regs,
+        };
+        let closes: Vec<_> = scopes[depth..]
+            .iter()
+            .flatten()
+            .filter_map(|(_, slot)| match slot {
+                Slot::Local(n) => Some(format!("close_upvalue {n} ;")),
+                _ => None,
+            })
+            .collect();
+        self.instructions.extend(closes);
+    }
+
+    /// How many scopes are open in the function being generated, or outside of
+    /// functions
+    fn scope_depth(&self) -> usize {
+        self.functions
+            .last()
+            .map_or(self.symbol_regs.len(), |function| function.scopes.len())
+    }
+
+    /// Turn the value on the stack into a float if it's going where a float was
+    /// declared and it might be an integer. The parser has already rejected values of
+    /// any other known type, and `tofloat` stops with an error if a value whose type
+    /// is only known when running isn't a number.
+    fn coerce(&mut self, expected: &BaseType, value: &BaseType) {
+        if *expected == BaseType::Float && matches!(value, BaseType::Integer | BaseType::Undefined)
+        {
+            self.push("tofloat ;".to_string());
+        }
+    }
+
+    /// Emit the instruction for a binary operator whose operands are on the stack
+    fn binary_op(&mut self, op: BinOp) {
+        match op {
+            BinOp::Add => {
+                self.push("add ;".to_string());
+            }
+            BinOp::Sub => {
+                self.push("sub ;".to_string());
+            }
+            BinOp::Mul => {
+                self.push("mul ;".to_string());
+            }
+            BinOp::Div => {
+                self.push("div ;".to_string());
+            }
+            BinOp::IntDiv => {
+                self.push("idiv ;".to_string());
+            }
+            BinOp::Mod => {
+                self.push("mod ;".to_string());
+            }
+            BinOp::Pow => {
+                self.push("pow ;".to_string());
+            }
+            BinOp::BitAnd => {
+                self.push("band ;".to_string());
+            }
+            BinOp::BitOr => {
+                self.push("bor ;".to_string());
+            }
+            BinOp::BitXor => {
+                self.push("bxor ;".to_string());
+            }
+            BinOp::Shl => {
+                self.push("shl ;".to_string());
+            }
+            BinOp::Shr => {
+                self.push("shr ;".to_string());
+            }
+            BinOp::Eq => {
+                self.push("equ ;".to_string());
+            }
+            BinOp::Ne => {
+                self.push("neq ;".to_string());
+            }
+            BinOp::Lt => {
+                self.push("lt ;".to_string());
+            }
+            BinOp::Le => {
+                self.push("le ;".to_string());
+            }
+            BinOp::Gt => {
+                self.push("gt ;".to_string());
+            }
+            BinOp::Ge => {
+                self.push("ge ;".to_string());
+            }
+
+            // These need jumps, see `short_circuit`
+            BinOp::And | BinOp::Or => {
+                unreachable!("`{op}` can't be applied to values on the stack")
+            }
+        }
+    }
+
+    /// Evaluate `node` into a spare slot so it can be loaded more than once. The slot
+    /// should be freed once it's done with.
+    fn spill(&mut self, node: &Node) -> Slot {
+        self.generate_code(node);
+        let slot = self.allocate();
+        self.store(slot);
+        slot
+    }
+
+    /// Generate each of `nodes`, leaving their values on the stack. Each value is
+    /// counted while the ones after it are generated, so that a `break` or
+    /// `continue` in them can drop it.
+    fn generate_operands(&mut self, nodes: &[Node]) {
+        let outer = self.temporaries;
+        for node in nodes {
+            self.generate_code(node);
+            self.temporaries += 1;
+        }
+        self.temporaries = outer;
+    }
+
+    /// Store the value of an assignment in its target. A compound assignment reads
+    /// the target first, and the parts of the target are spilled so that they're only
+    /// evaluated once.
+    fn generate_assignment(&mut self, node: &Node) {
+        let target = &node.children[0];
+        let value = &node.children[1];
+        let op = match node.node_type {
+            NodeType::CompoundAssignment(op) => Some(op),
+            _ => None,
+        };
+
+        match &target.node_type {
+            NodeType::Ident(name, _) => {
+                let var_reg = self
+                    .get_variable(name)
+                    .unwrap_or_else(|| panic!("Variable {} not found", name));
+                self.generate_code(value);
+                match op {
+                    Some(op) => {
+                        self.load(var_reg);
+                        self.binary_op(op);
+                    }
+                    None => self.coerce(&target.return_type, &value.return_type),
+                }
+                self.store(var_reg);
+            }
+            NodeType::ElementIndex => {
+                let Some(op) = op else {
+                    self.generate_operands(&target.children);
+                    self.temporaries += 2;
+                    self.generate_code(value);
+                    self.temporaries -= 2;
+                    self.coerce(&target.return_type, &value.return_type);
+                    self.push("setindex ;".to_string());
+                    return;
+                };
+                let array_reg = self.spill(&target.children[0]);
+                let index_reg = self.spill(&target.children[1]);
+                self.load(array_reg);
+                self.load(index_reg);
+                self.temporaries += 2;
+                self.generate_code(value);
+                self.temporaries -= 2;
+                self.load(array_reg);
+                self.load(index_reg);
+                self.push("index ;".to_string());
+                self.binary_op(op);
+                self.push("setindex ;".to_string());
+                self.free(array_reg);
+                self.free(index_reg);
+            }
+            NodeType::FieldAccess(name) => {
+                let loc = self.get_string_location(name);
+                let Some(op) = op else {
+                    self.generate_code(&target.children[0]);
+                    self.temporaries += 1;
+                    self.generate_code(value);
+                    self.temporaries -= 1;
+                    self.coerce(&target.return_type, &value.return_type);
+                    self.push(format!("setfield {loc} ;"));
+                    return;
+                };
+                let object_reg = self.spill(&target.children[0]);
+                self.load(object_reg);
+                self.temporaries += 1;
+                self.generate_code(value);
+                self.temporaries -= 1;
+                self.load(object_reg);
+                self.push(format!("getfield {loc} ;"));
+                self.binary_op(op);
+                self.push(format!("setfield {loc} ;"));
+                self.free(object_reg);
+            }
+            _ => panic!("Can't assign to {}", target.node_type),
+        }
+    }
+
+    /// Make a name for a new jump target
+    fn new_label(&mut self) -> String {
+        self.label_count += 1;
+        format!("L{}", self.label_count)
+    }
+
+    /// Mark where `label` points to
+    fn push_label(&mut self, label: &str) {
+        self.push(format!("{label}:"));
+    }
+
+    /// Lower `and`/`or` so that the right operand is only evaluated when the left
+    /// one doesn't settle the result. The left operand is kept as the result when
+    /// it does.
+    fn short_circuit(&mut self, node: &Node, op: BinOp) {
+        // Binary operator children are stored right operand first
+        let (rhs, lhs) = (&node.children[0], &node.children[1]);
+        let end = self.new_label();
+        self.generate_code(lhs);
+        self.push("dup ;".to_string());
+        let jump = if op == BinOp::And { "jmpf" } else { "jmpt" };
+        self.push(format!("{jump} {end} ;"));
+        self.push("pop ;".to_string());
+        self.generate_code(rhs);
+        self.push_label(&end);
+    }
+
+    /// Generate a statement. Expressions used as statements have their value dropped.
+    fn generate_statement(&mut self, node: &Node) {
+        self.generate_code(node);
+        if !node.node_type.is_statement() {
+            self.push("pop ;".to_string());
+        }
+    }
+
+    /// Generate the statements of a block in a scope of their own, leaving the block's
+    /// value on the stack. That's the value of the last statement if it's an
+    /// expression, or nil.
+    fn generate_block(&mut self, node: &Node) {
+        let Some((last, rest)) = node.children.split_last() else {
+            self.push("push nil ;".to_string());
+            return;
+        };
+        self.push_scope();
+        for child in rest {
+            self.generate_statement(child);
+        }
+        self.generate_code(last);
+        if last.node_type.is_statement() {
+            self.push("push nil ;".to_string());
+        }
+        self.pop_scope();
+    }
+
+    /// Generate an `if` with its branches. The branch that runs leaves its value on
+    /// the stack, and a missing `else` gives nil.
+    fn generate_if(&mut self, node: &Node) {
+        let else_label = self.new_label();
+        let end = self.new_label();
+        self.generate_code(&node.children[0]);
+        self.push(format!("jmpf {else_label} ;"));
+        self.generate_code(&node.children[1]);
+        self.push(format!("jmp {end} ;"));
+        self.push_label(&else_label);
+        match node.children.get(2) {
+            Some(branch) => self.generate_code(branch),
+            None => self.push("push nil ;".to_string()),
+        }
+        self.push_label(&end);
+    }
+
+    /// Generate a `while` or `loop`. The condition is checked at the top, and the end
+    /// of the body jumps back to it. Loops are statements, so nothing is left on the
+    /// stack.
+    fn generate_loop(&mut self, node: &Node, name: Option<&str>) {
+        let start = self.new_label();
+        let end = self.new_label();
+        self.push_label(&start);
+        if matches!(node.node_type, NodeType::While(_)) {
+            self.generate_code(&node.children[0]);
+            self.push(format!("jmpf {end} ;"));
+        }
+
+        let body = node.children.last().expect("A loop needs a body");
+        self.generate_loop_body(body, name, &start, &end, |_| {});
+
+        self.push(format!("jmp {start} ;"));
+        self.push_label(&end);
+    }
+
+    /// Generate a `for` loop by counting an index up to the length of the range or
+    /// array, and setting the loop variables from it at the top of each pass. A
+    /// range written in the loop is never built as an array.
+    fn generate_for(&mut self, node: &Node, name: Option<&str>) {
+        let [variable, iterable, body] = node.children.as_slice() else {
+            panic!("A loop needs a variable, something to go over and a body");
+        };
+        let NodeType::Ident(variable, _) = &variable.node_type else {
+            panic!("Loop variables must be names, found {}", variable.node_type);
+        };
+
+        // The start of a range or the array, the number of passes and the index
+        let source = self.allocate();
+        let length = self.allocate();
+        let index = self.allocate();
+        match iterable.node_type {
+            NodeType::Range(inclusive) => {
+                let (end, start) = (&iterable.children[0], &iterable.children[1]);
+                self.generate_code(start);
+                self.store(source);
+                self.load(source);
+                self.temporaries += 1;
+                self.generate_code(end);
+                self.temporaries -= 1;
+                if inclusive {
+                    self.push("push 1 ;".to_string());
+                    self.push("add ;".to_string());
+                }
+                self.push("sub ;".to_string());
+            }
+            _ => {
+                self.generate_code(iterable);
+                self.push("dup ;".to_string());
+                self.store(source);
+                self.push("len ;".to_string());
+            }
+        }
+        self.store(length);
+        self.push("push 0 ;".to_string());
+        self.store(index);
+
+        let start = self.new_label();
+        let next = self.new_label();
+        let end = self.new_label();
+        self.push_label(&start);
+        self.load(length);
+        self.load(index);
+        self.push("lt ;".to_string());
+        self.push(format!("jmpf {end} ;"));
+
+        // The loop variable is declared afresh in the body on every pass, so
+        // closures made in different passes capture different variables
+        let is_range = matches!(iterable.node_type, NodeType::Range(_));
+        self.generate_loop_body(body, name, &next, &end, |generator| {
+            let slot = generator.store_variable(variable);
+            generator.load(source);
+            generator.load(index);
+            if is_range {
+                generator.push("add ;".to_string());
+            } else {
+                generator.push("index ;".to_string());
+            }
+            generator.store(slot);
+        });
+        self.push_label(&next);
+        self.push("push 1 ;".to_string());
+        self.load(index);
+        self.push("add ;".to_string());
+        self.store(index);
+        self.push(format!("jmp {start} ;"));
+        self.push_label(&end);
+
+        for reg in [source, length, index] {
+            self.free(reg);
+        }
+    }
+
+    /// Generate the statements of a loop's body in a scope of their own, after
+    /// `declare` has set up any loop variables in it. `continue` and `break` jump to
+    /// the given labels.
+    fn generate_loop_body(
+        &mut self,
+        body: &Node,
+        name: Option<&str>,
+        next: &str,
+        end: &str,
+        declare: impl FnOnce(&mut Self),
+    ) {
+        self.loops.push(LoopLabels {
+            name: name.map(str::to_string),
+            continue_label: next.to_string(),
+            break_label: end.to_string(),
+            depth: self.scope_depth(),
+            temporaries: self.temporaries,
+        });
+        self.push_scope();
+        declare(self);
+        for statement in &body.children {
+            self.generate_statement(statement);
+        }
+        self.pop_scope();
+        self.loops.pop();
+    }
+
+    /// Generate a function. The body is skipped over where it's declared, and then a
+    /// closure is made from it and the variables it captures. A named function is
+    /// stored in a variable, which is bound first so the body can call itself, and
+    /// an anonymous one is left on the stack.
+    fn generate_function(&mut self, node: &Node, def: &FunctionDef) {
+        let slot = def.name.as_ref().map(|name| self.store_variable(name));
+        let (body, params) = node.children.split_last().expect("A function needs a body");
+        let entry = self.new_label();
+        let skip = self.new_label();
+        self.push(format!("jmp {skip} ;"));
+        self.push_label(&entry);
+
+        // Arguments are the first locals of the frame, in order
+        let outer_loops = std::mem::take(&mut self.loops);
+        let outer_temporaries = std::mem::take(&mut self.temporaries);
+        self.functions.push(FunctionScope {
+            locals: Registers::new(MAX_LOCALS),
+            scopes: vec![Scope::new()],
+            upvalues: def.captures.clone(),
+            captured: HashSet::new(),
+            returns: node.return_type.clone(),
+        });
+        for param in params {
+            let NodeType::Ident(name, _) = &param.node_type else {
+                panic!("Parameters must be names, found {}", param.node_type);
+            };
+            let slot = self.store_variable(name);
+            if param.return_type == BaseType::Float {
+                self.load(slot);
+                self.coerce(&param.return_type, &BaseType::Undefined);
+                self.store(slot);
+            }
+        }
+        // The value of the body is returned if it doesn't return first
+        self.generate_block(body);
+        self.coerce(&node.return_type, &body.return_type);
+        self.push("ret ;".to_string());
+        self.functions.pop();
+        self.loops = outer_loops;
+        self.temporaries = outer_temporaries;
+
+        // Each capture is a local of this frame or one of its own upvalues
+        self.push_label(&skip);
+        let mut closure = format!("closure {entry} {} {}", params.len(), def.captures.len());
+        for name in &def.captures {
+            let capture = match self.get_variable(name) {
+                Some(Slot::Local(n)) => {
+                    match self.functions.last_mut() {
+                        Some(function) => function.captured.insert(n),
+                        None => self.captured.insert(n),
+                    };
+                    format!(" 1 {n}")
+                }
+                Some(Slot::Upvalue(n)) => format!(" 0 {n}"),
+                _ => panic!("`{name}` isn't a variable of an enclosing block"),
+            };
+            closure.push_str(&capture);
+        }
+        self.push(format!("{closure} ;"));
+        if let Some(slot) = slot {
+            self.store(slot);
+        }
+    }
+
+    /// Jump to the start or the end of the loop that a `continue` or `break` names,
+    /// or the innermost one
+    fn generate_break(&mut self, name: Option<&str>, is_break: bool) {
+        let labels = self
+            .loops
+            .iter()
+            .rev()
+            .find(|l| name.is_none() || l.name.as_deref() == name)
+            .expect("`break` and `continue` need to be in a loop");
+        let target = if is_break {
+            labels.break_label.clone()
+        } else {
+            labels.continue_label.clone()
+        };
+        let depth = labels.depth;
+        // Values that expressions around the jump were going to use are dropped
+        for _ in labels.temporaries..self.temporaries {
+            self.push("pop ;".to_string());
+        }
+        self.close_scopes(depth);
+        self.push(format!("jmp {target} ;"));
     }
 
     fn push(&mut self, instruction: String) {
@@ -104,48 +778,65 @@ impl IrGenerator {
                 self.push(format!("push {} ;", value));
             }
             NodeType::Float(value) => {
-                self.push(format!("push {} ;", value));
+                self.push(format!("push {:?} ;", value));
             }
             NodeType::Text(value) => {
-                let loc = self.get_string_location(&*value);
-                self.push(format!("push {} ;", loc));
+                let loc = self.get_string_location(&value);
+                self.push(format!("const {} ;", loc));
+            }
+            NodeType::Interpolation => {
+                // Start from an empty string if the first piece is an expression so
+                // that the result is always text
+                let outer = self.temporaries;
+                let mut pieces = 0;
+                if !matches!(
+                    node.children.first().map(|c| &c.node_type),
+                    Some(NodeType::Text(_))
+                ) {
+                    let loc = self.get_string_location("");
+                    self.push(format!("const {} ;", loc));
+                    pieces += 1;
+                }
+                for child in &node.children {
+                    self.temporaries = outer + pieces.min(1);
+                    self.generate_code(child);
+                    if pieces > 0 {
+                        self.push("concat ;".to_string());
+                    }
+                    pieces += 1;
+                }
+                self.temporaries = outer;
             }
             NodeType::Boolean(value) => {
                 self.push(format!("push {} ;", value));
             }
+            NodeType::Char(value) => {
+                self.push(format!("push {:?} ;", value));
+            }
+            NodeType::Nil => {
+                self.push("push nil ;".to_string());
+            }
 
+            NodeType::BinaryOp(op @ (BinOp::And | BinOp::Or)) => {
+                self.short_circuit(node, op);
+            }
             NodeType::BinaryOp(op) => {
-                for child in &node.children {
-                    self.generate_code(child);
-                }
-                match op {
-                    BinOp::Add => {
-                        self.push(format!("add ;"));
-                    }
-                    BinOp::Sub => {
-                        self.push(format!("sub ;"));
-                    }
-                    BinOp::Mul => {
-                        self.push(format!("mul ;"));
-                    }
-                    BinOp::Div => {
-                        self.push(format!("div ;"));
-                    }
-                    BinOp::Pow => {
-                        self.push(format!("pow ;"));
-                    }
-
-                    BinOp::And => {}
-                    BinOp::Or => {}
-                }
+                self.generate_operands(&node.children);
+                self.binary_op(op);
             }
             NodeType::UnaryOp(op) => {
                 for child in &node.children {
                     self.generate_code(child);
                 }
                 match op {
-                    UnOp::Neg | UnOp::Not => {
-                        self.push(format!("neg ;"));
+                    UnOp::Neg => {
+                        self.push("neg ;".to_string());
+                    }
+                    UnOp::Not => {
+                        self.push("not ;".to_string());
+                    }
+                    UnOp::BitNot => {
+                        self.push("bnot ;".to_string());
                     }
                 }
             }
@@ -166,18 +857,21 @@ impl IrGenerator {
                 let data_type = node.return_type;
 
                 // Check the next child in an assignment operator
-                if let Some(next_node) = node.children.get(0) {
+                if let Some(next_node) = node.children.first() {
                     // Generate the expression that gets assigned to the variable
                     self.generate_code(next_node);
-
-                    self.push(format!("store ;",));
+                    self.coerce(&data_type, &next_node.return_type);
+                } else {
+                    self.push("push nil ;".to_string());
                 }
+                let var_reg = self.store_variable(&var_name);
+                self.store(var_reg);
             }
             NodeType::Print => {
                 for c in &node.children {
                     self.generate_code(c);
                 }
-                self.push(format!("print ;"));
+                self.push("print ;".to_string());
             }
             NodeType::Ident(name, node_type) => {
                 if let Some(var_reg) = self.get_variable(&name) {
@@ -185,21 +879,151 @@ impl IrGenerator {
 
                     // Load the contents of the location of the variable to the newly
                     // allocated register
-                    self.push(format!("load {var_reg}"));
+                    self.load(var_reg);
 
                     for child in &node.children {}
                 } else {
-                    panic!("Variable {} not found", name);
+                    unreachable!("The parser reports unknown variables, found {name}");
                 }
             }
-            // We don't need to capture the internal elements here because we're drilling
-            // down into the elements
-            NodeType::Array(_) => {}
-
-            NodeType::Root => {
+            NodeType::Array(_) => {
+                // The elements of an array declared to hold floats are made floats
+                let element = match &node.annotation {
+                    Some(TypeAnnotation::Array(element)) => element.base_type(),
+                    _ => BaseType::Undefined,
+                };
+                let outer = self.temporaries;
                 for child in &node.children {
                     self.generate_code(child);
+                    self.coerce(&element, &child.return_type);
+                    self.temporaries += 1;
                 }
+                self.temporaries = outer;
+                self.push(format!("newarray {} ;", node.children.len()));
+            }
+            NodeType::ElementIndex => {
+                // The array then the index
+                self.generate_operands(&node.children);
+                self.push("index ;".to_string());
+            }
+            NodeType::FieldAccess(name) => {
+                self.generate_code(&node.children[0]);
+                let loc = self.get_string_location(&name);
+                self.push(format!("getfield {loc} ;"));
+            }
+            NodeType::StructLiteral(literal) => {
+                // The field values in declaration order, then their names
+                let mut instruction = format!(
+                    "newstruct {} {}",
+                    self.get_string_location(&literal.name),
+                    literal.fields.len()
+                );
+                let outer = self.temporaries;
+                for (value, (field, field_type)) in node.children.iter().zip(&literal.fields) {
+                    self.generate_code(value);
+                    self.coerce(field_type, &value.return_type);
+                    self.temporaries += 1;
+                    let loc = self.get_string_location(field);
+                    instruction.push_str(&format!(" {loc}"));
+                }
+                self.temporaries = outer;
+                self.push(format!("{instruction} ;"));
+            }
+            NodeType::Variant(variant) => {
+                let outer = self.temporaries;
+                for (value, value_type) in node.children.iter().zip(&variant.payload) {
+                    self.generate_code(value);
+                    self.coerce(value_type, &value.return_type);
+                    self.temporaries += 1;
+                }
+                self.temporaries = outer;
+                let enum_loc = self.get_string_location(&variant.enum_name);
+                let name_loc = self.get_string_location(&variant.name);
+                self.push(format!(
+                    "newvariant {enum_loc} {name_loc} {} {} ;",
+                    variant.tag,
+                    node.children.len()
+                ));
+            }
+            // Struct and enum declarations only matter to the parser
+            NodeType::Struct(_) | NodeType::Enum(_) => {}
+            NodeType::Function(def) => {
+                self.generate_function(node, &def);
+            }
+            NodeType::Call => {
+                // The function then its arguments
+                self.generate_operands(&node.children);
+                self.push(format!("call {} ;", node.children.len() - 1));
+            }
+            NodeType::Return => {
+                match node.children.first() {
+                    Some(value) => {
+                        self.generate_code(value);
+                        let returns = self.functions.last().map(|f| f.returns.clone());
+                        if let Some(returns) = returns {
+                            self.coerce(&returns, &value.return_type);
+                        }
+                    }
+                    None => self.push("push nil ;".to_string()),
+                }
+                self.push("ret ;".to_string());
+            }
+            NodeType::Assignment | NodeType::CompoundAssignment(_) => {
+                self.generate_assignment(node);
+            }
+
+            NodeType::Root => {
+                // Globals can be used by functions declared before them, and the
+                // functions declared here can be called before their declaration, so
+                // they're made first
+                self.declare_globals(node);
+                let functions = |child: &&Node| matches!(&child.node_type, NodeType::Function(def) if def.name.is_some());
+                for child in node.children.iter().filter(functions) {
+                    self.generate_statement(child);
+                }
+                // The value of the last expression is left on the stack for the REPL
+                // to show
+                let last = node.children.len().saturating_sub(1);
+                for (i, child) in node.children.iter().enumerate() {
+                    if functions(&child) {
+                        continue;
+                    }
+                    if i == last {
+                        self.generate_code(child);
+                    } else {
+                        self.generate_statement(child);
+                    }
+                }
+            }
+            NodeType::Block => {
+                self.generate_block(node);
+            }
+            NodeType::If => {
+                self.generate_if(node);
+            }
+            NodeType::While(name) | NodeType::Loop(name) => {
+                self.generate_loop(node, name.as_deref().map(String::as_str));
+            }
+            NodeType::For(name) => {
+                self.generate_for(node, name.as_deref().map(String::as_str));
+            }
+            NodeType::Range(inclusive) => {
+                // The end then the start
+                self.generate_code(&node.children[0]);
+                if inclusive {
+                    self.push("push 1 ;".to_string());
+                    self.push("add ;".to_string());
+                }
+                self.temporaries += 1;
+                self.generate_code(&node.children[1]);
+                self.temporaries -= 1;
+                self.push("range ;".to_string());
+            }
+            NodeType::Break(name) => {
+                self.generate_break(name.as_deref().map(String::as_str), true);
+            }
+            NodeType::Continue(name) => {
+                self.generate_break(name.as_deref().map(String::as_str), false);
             }
 
             _ => {
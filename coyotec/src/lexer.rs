@@ -1,93 +1,404 @@
 #![allow(dead_code, unused_variables, unused_imports)]
-use crate::tokens::{BaseType, Location, Token, TokenType};
+use crate::errors::{Diagnostic, DiagnosticKind, Diagnostics};
+use crate::sourcemap::SourceMap;
+use crate::tokens::{
+    BaseType, FileId, Location, Span, StringPart, Token, TokenTrivia, TokenType, Trivia, TriviaKind,
+};
 use anyhow::{anyhow, Context, Result};
-use std::iter::Peekable;
-use std::str::Chars;
+use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
+use std::rc::Rc;
 
+#[derive(Clone)]
 pub enum SourceType {
     Interactive,
     Test,
     File(String),
 }
 
+/// Whether the lexer keeps the text that isn't part of any token
+#[derive(Clone, Copy, PartialEq)]
+pub enum LexMode {
+    /// Whitespace and comments are dropped
+    Normal,
+    /// Every token carries its own text and the whitespace and comments around it, so
+    /// the source can be rebuilt exactly with `tokens_to_text`
+    Lossless,
+}
+
 struct Source<'a> {
-    code: Peekable<Chars<'a>>,
+    // The code being lexed. Tokens are sliced out of it by byte offset.
+    text: &'a str,
+    // Byte offset of `text` within the file
+    base_offset: usize,
     source_type: SourceType,
+    file_id: FileId,
 }
 
 struct Lexer<'a> {
     source: Source<'a>,
     location: Location,
-    error_mode: bool,
+    // Byte offset of the next character
+    offset: usize,
+    // Where the token being lexed started
+    start_location: Location,
+    start_offset: usize,
+    diagnostics: Vec<Diagnostic>,
+    mode: LexMode,
+    // Trivia waiting to be attached to the next token
+    leading_trivia: Vec<Trivia>,
+    // Every identifier seen so far, so repeated names share one allocation
+    names: HashSet<Rc<str>>,
 }
 
 impl<'a> Lexer<'a> {
-    pub fn new(source: Source<'a>) -> Self {
+    pub fn new(source: Source<'a>, mode: LexMode) -> Self {
+        let offset = source.base_offset;
         Self {
             source,
             location: Location::new(),
-            error_mode: false,
+            offset,
+            start_location: Location::new(),
+            start_offset: offset,
+            diagnostics: Vec::new(),
+            mode,
+            leading_trivia: Vec::new(),
+            names: HashSet::new(),
         }
     }
 
+    /// The source text that hasn't been consumed yet
+    #[inline]
+    fn rest(&self) -> &'a str {
+        &self.source.text[self.offset - self.source.base_offset..]
+    }
+
+    /// The source text between two byte offsets in the file
+    #[inline]
+    fn slice(&self, start: usize, end: usize) -> &'a str {
+        let base = self.source.base_offset;
+        &self.source.text[start - base..end - base]
+    }
+
+    #[inline]
     pub fn advance(&mut self) -> Option<char> {
-        let value = self.source.code.next();
+        let value = self.peek();
         self.location.increment(1);
+        if let Some(c) = value {
+            self.offset += c.len_utf8();
+        }
         value
     }
 
-    pub fn peek(&mut self) -> Option<&char> {
-        self.source.code.peek()
+    /// Mark the next character as the start of a token
+    #[inline]
+    fn start_token(&mut self) {
+        self.start_location = self.location;
+        self.start_offset = self.offset;
+    }
+
+    #[inline]
+    pub fn peek(&self) -> Option<char> {
+        // Most code is ASCII, which can be read without decoding
+        let pos = self.offset - self.source.base_offset;
+        match self.source.text.as_bytes().get(pos) {
+            Some(&b) if b.is_ascii() => Some(b as char),
+            Some(_) => self.rest().chars().next(),
+            None => None,
+        }
+    }
+
+    /// Peek at the character after the next one
+    pub fn peek_next(&self) -> Option<char> {
+        self.peek_nth(1)
+    }
+
+    /// Look `n` characters past the next one without consuming anything
+    fn peek_nth(&self, n: usize) -> Option<char> {
+        self.rest().chars().nth(n)
+    }
+
+    /// Consume the next character only if it's the one expected
+    #[inline]
+    pub fn match_char(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.advance();
+            return true;
+        }
+        false
     }
 
     pub fn newline(&mut self) {
         self.location.newline();
     }
 
-    pub fn get_number(&mut self) -> String {
-        let mut snum = String::new();
-        while let Some(&x) = self.peek() {
-            if x.is_ascii_digit() {
-                snum.push(x);
+    /// Skip the digits of a number in the given radix and return them. `_` separators
+    /// are allowed anywhere between digits.
+    pub fn get_number(&mut self, radix: u32) -> &'a str {
+        let start = self.offset;
+        while let Some(x) = self.peek() {
+            if !x.is_digit(radix) && x != '_' {
+                break;
+            }
+            self.advance();
+        }
+        self.slice(start, self.offset)
+    }
+
+    /// Lex a number literal starting with the digit `first`, which has been consumed.
+    ///
+    /// Integers can be written in decimal, hex (`0xFF`), binary (`0b1010`) or octal
+    /// (`0o17`). Floats have a fraction, an exponent (`1.5e-3`) or both. Either kind can
+    /// be followed by an `i` or `f` suffix to pick the type, so `2f` is a float. Hex
+    /// literals can only take the `i` suffix since `f` is a hex digit.
+    pub fn number(&mut self, first: char) -> Option<TokenType> {
+        let radix = match (first, self.peek()) {
+            ('0', Some('x')) | ('0', Some('X')) => 16,
+            ('0', Some('b')) | ('0', Some('B')) => 2,
+            ('0', Some('o')) | ('0', Some('O')) => 8,
+            _ => 10,
+        };
+
+        let mut is_float = false;
+        let digits = if radix == 10 {
+            self.get_number(10);
+
+            // A dot only makes this a float if a digit follows, so `0..10` is a range
+            if self.peek() == Some('.') && self.peek_next().is_some_and(|n| n.is_ascii_digit()) {
+                self.advance();
+                is_float = true;
+                self.get_number(10);
+            }
+
+            if matches!(self.peek(), Some('e') | Some('E'))
+                && self
+                    .peek_next()
+                    .is_some_and(|n| n.is_ascii_digit() || n == '+' || n == '-')
+            {
                 self.advance();
-            } else {
+                is_float = true;
+                if matches!(self.peek(), Some('+') | Some('-')) {
+                    self.advance();
+                }
+                let exponent = self.get_number(10);
+                if !exponent.contains(|x: char| x.is_ascii_digit()) {
+                    self.error(
+                        DiagnosticKind::InvalidNumber,
+                        "Missing digits in float exponent",
+                    );
+                    return None;
+                }
+            }
+            self.lexeme()
+        } else {
+            // Skip over the radix prefix
+            self.advance();
+            let digits = self.get_number(radix);
+            if !digits.contains(|x: char| x != '_') {
+                self.error(
+                    DiagnosticKind::InvalidNumber,
+                    "Missing digits after number prefix",
+                );
+                return None;
+            }
+            digits
+        };
+        // Separators only cost an allocation when they're used
+        let digits = if digits.contains('_') {
+            Cow::Owned(digits.replace('_', ""))
+        } else {
+            Cow::Borrowed(digits)
+        };
+
+        let suffix_start = self.offset;
+        while let Some(x) = self.peek() {
+            if !x.is_alphanumeric() && x != '_' {
+                break;
+            }
+            self.advance();
+        }
+        match self.slice(suffix_start, self.offset) {
+            "" => {}
+            "i" if !is_float => {}
+            "f" if radix == 10 => is_float = true,
+            "i" => {
+                self.error(
+                    DiagnosticKind::InvalidNumber,
+                    "A float literal can't have an integer suffix",
+                );
+                return None;
+            }
+            suffix => {
+                self.error(
+                    DiagnosticKind::InvalidNumber,
+                    &format!("Invalid suffix `{suffix}` for number literal"),
+                );
+                return None;
+            }
+        }
+
+        if is_float {
+            return match digits.parse::<f64>() {
+                Ok(num) if num.is_finite() => Some(TokenType::Float(num)),
+                _ => {
+                    self.error(
+                        DiagnosticKind::InvalidNumber,
+                        "Float literal is out of range",
+                    );
+                    None
+                }
+            };
+        }
+        match i64::from_str_radix(&digits, radix) {
+            Ok(num) => Some(TokenType::Integer(num)),
+            Err(_) => {
+                self.error(
+                    DiagnosticKind::InvalidNumber,
+                    "Integer literal is too large",
+                );
+                None
+            }
+        }
+    }
+
+    /// Lex an identifier or keyword. The first character has been consumed.
+    fn identifier(&mut self) -> TokenType {
+        while let Some(x) = self.peek() {
+            if !x.is_alphanumeric() && x != '_' {
+                break;
+            }
+            self.advance();
+        }
+        let ident = self.lexeme();
+        // And if it isn't a keyword, it's an identifier
+        keyword(ident).unwrap_or_else(|| TokenType::Identifier(self.intern(ident)))
+    }
+
+    /// Whether the quote just consumed starts a loop label rather than a char. `'a'`
+    /// is a char but `'a` or `'outer` is a label.
+    fn at_label(&self) -> bool {
+        let rest = self.rest();
+        let name_len = rest
+            .find(|x: char| !x.is_alphanumeric() && x != '_')
+            .unwrap_or(rest.len());
+        let label_start = self.peek().is_some_and(|x| x.is_alphabetic() || x == '_');
+        label_start && !rest[name_len..].starts_with('\'')
+    }
+
+    /// Lex a loop label such as `'outer`. The quote has been consumed.
+    fn label(&mut self) -> TokenType {
+        let start = self.offset;
+        while let Some(x) = self.peek() {
+            if !x.is_alphanumeric() && x != '_' {
                 break;
             }
+            self.advance();
+        }
+        let name = self.slice(start, self.offset);
+        TokenType::Label(self.intern(name))
+    }
+
+    /// Share one copy of each identifier between all the tokens that use it
+    fn intern(&mut self, name: &str) -> Rc<str> {
+        if let Some(name) = self.names.get(name) {
+            return name.clone();
         }
-        snum
+        let name: Rc<str> = Rc::from(name);
+        self.names.insert(name.clone());
+        name
+    }
+
+    /// The source text consumed since `start_token`
+    fn lexeme(&self) -> &'a str {
+        self.slice(self.start_offset, self.offset)
     }
 
+    /// Make a token covering everything consumed since `start_token`
     pub fn make_token(&mut self, token_type: TokenType) -> Token {
+        let trivia = (self.mode == LexMode::Lossless).then(|| {
+            Box::new(TokenTrivia {
+                leading: std::mem::take(&mut self.leading_trivia),
+                text: self.lexeme().to_string(),
+                trailing: Vec::new(),
+            })
+        });
         Token {
             token_type,
-            location: self.location,
+            location: self.start_location,
+            span: Span::new(self.source.file_id, self.start_offset, self.offset),
+            trivia,
         }
     }
 
-    pub fn multi_line_comment(&mut self) {
-        self.advance();
-        while let Some(&x) = self.peek() {
-            if x == '\n' {
-                self.newline();
+    /// Consume the whitespace or comment at the start of the remaining source, if
+    /// there is one. Doc comments aren't trivia since the parser needs them.
+    fn trivia(&mut self) -> Option<TriviaKind> {
+        self.start_token();
+        let c = self.peek()?;
+        let next = if c == '/' { self.peek_next() } else { None };
+        let kind = match (c, next) {
+            (' ' | '\t' | '\r', _) => {
+                while matches!(self.peek(), Some(' ' | '\t' | '\r')) {
+                    self.advance();
+                }
+                TriviaKind::Whitespace
+            }
+            // `///` is a doc comment but `////` is just a comment
+            ('/', Some('/')) if self.peek_nth(2) != Some('/') || self.peek_nth(3) == Some('/') => {
                 self.advance();
-                continue;
+                self.single_line_comment();
+                TriviaKind::LineComment
             }
-            if x == '*' {
+            // `/** */` is a doc comment but `/**/` and `/*** */` are not
+            ('/', Some('*'))
+                if self.peek_nth(2) != Some('*')
+                    || matches!(self.peek_nth(3), Some('*') | Some('/')) =>
+            {
                 self.advance();
-                if let Some(&x) = self.peek() {
-                    if x == '/' {
-                        self.advance();
-                        break;
+                self.multi_line_comment();
+                TriviaKind::BlockComment
+            }
+            _ => return None,
+        };
+        Some(kind)
+    }
+
+    /// The trivia just consumed by `trivia`
+    fn trivia_text(&self, kind: TriviaKind) -> Trivia {
+        Trivia {
+            kind,
+            text: self.lexeme().to_string(),
+        }
+    }
+
+    /// Skip a block comment, allowing `/* */` pairs to nest. Returns false if
+    /// the input ends before the comment is closed
+    pub fn multi_line_comment(&mut self) -> bool {
+        self.advance();
+        let mut depth = 1;
+        while let Some(x) = self.advance() {
+            match x {
+                '\n' => self.newline(),
+                '/' if self.match_char('*') => depth += 1,
+                '*' if self.match_char('/') => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return true;
                     }
                 }
+                _ => {}
             }
-            self.advance();
         }
+        self.error(
+            DiagnosticKind::UnterminatedComment,
+            "Unterminated block comment",
+        );
+        false
     }
 
     pub fn single_line_comment(&mut self) {
         self.advance();
-        while let Some(&x) = self.peek() {
+        while let Some(x) = self.peek() {
             if x == '\n' {
                 break;
             }
@@ -95,173 +406,1278 @@ impl<'a> Lexer<'a> {
         }
     }
 
-    pub fn report_error(&mut self, err_msg: &str) {
-        self.error_mode = true;
-        println!(
-            "{} at line {} position {}",
-            err_msg, self.location.line, self.location.column
-        );
+    /// Record a problem with the token being lexed
+    fn error(&mut self, kind: DiagnosticKind, message: &str) {
+        let (location, offset) = (self.start_location, self.start_offset);
+        self.error_at(kind, message, location, offset);
     }
 
-    fn make_error(&mut self, err_msg: &str) -> anyhow::Error {
-        self.error_mode = true;
-        anyhow!(
-            "{} at line {} position {}",
-            err_msg,
-            self.location.line,
-            self.location.column
-        )
+    /// Record a problem that starts at `location`, which is `offset` bytes into the
+    /// file, and runs up to the current position
+    fn error_at(&mut self, kind: DiagnosticKind, message: &str, location: Location, offset: usize) {
+        self.diagnostics.push(Diagnostic {
+            kind,
+            message: message.to_string(),
+            location,
+            span: Span::new(self.source.file_id, offset, self.offset),
+        });
     }
-}
 
-pub fn lex(code: &str, source_type: SourceType) -> Result<Vec<Token>> {
-    let mut tokens: Vec<Token> = Vec::new();
+    /// The location and offset of the character just consumed, which must be ASCII
+    fn previous_char(&self) -> (Location, usize) {
+        let mut location = self.location;
+        location.column -= 1;
+        (location, self.offset - 1)
+    }
 
-    let mut lexer = Lexer::new(Source {
-        code: code.chars().peekable(),
-        source_type,
-    });
+    /// Lex a double-quoted string. The opening quote has already been consumed.
+    /// Handles the empty string `""` and triple-quoted
+    /// multi-line strings, and decodes escape sequences in both. A string containing
+    /// `${...}` becomes an `Interpolated` token.
+    pub fn string(&mut self) -> Option<TokenType> {
+        if self.peek() == Some('"') {
+            self.advance();
+            if self.peek() != Some('"') {
+                return Some(TokenType::Text(String::new()));
+            }
+            self.advance();
+            return self.multi_line_string();
+        }
+
+        let mut parts = Vec::new();
+        let mut s = String::new();
+        while let Some(x) = self.peek() {
+            self.advance();
+            match x {
+                '"' => return Some(finish_string(parts, s)),
+                '\\' => {
+                    if let Some(c) = self.escape() {
+                        s.push(c);
+                    }
+                }
+                '$' if self.peek() == Some('{') => self.interpolation(&mut parts, &mut s),
+                _ => {
+                    if x == '\n' {
+                        self.newline();
+                    }
+                    s.push(x);
+                }
+            }
+        }
+        self.error(DiagnosticKind::UnterminatedString, "Unterminated string");
+        None
+    }
 
-    while let Some(&c) = lexer.peek() {
-        // Get rid of whitespace characters
-        if [' ', '\t'].contains(&c) {
-            lexer.advance();
-            continue;
+    /// Lex the body of a `"""` string up to the closing `"""`. A newline straight after
+    /// the opening quotes is dropped so the text can start on its own line.
+    fn multi_line_string(&mut self) -> Option<TokenType> {
+        if self.peek() == Some('\n') {
+            self.advance();
+            self.newline();
         }
 
-        if c.is_ascii_digit() {
-            let mut snum = c.to_string();
-            lexer.advance();
-            snum.push_str(&lexer.get_number());
+        let mut parts = Vec::new();
+        let mut s = String::new();
+        let mut quotes = 0;
+        while let Some(x) = self.peek() {
+            self.advance();
+            if x == '"' {
+                quotes += 1;
+                if quotes == 3 {
+                    return Some(finish_string(parts, s));
+                }
+                continue;
+            }
+            s.extend(std::iter::repeat_n('"', quotes));
+            quotes = 0;
+            match x {
+                '\\' => {
+                    if let Some(c) = self.escape() {
+                        s.push(c);
+                    }
+                }
+                '$' if self.peek() == Some('{') => self.interpolation(&mut parts, &mut s),
+                _ => {
+                    if x == '\n' {
+                        self.newline();
+                    }
+                    s.push(x);
+                }
+            }
+        }
+        self.error(DiagnosticKind::UnterminatedString, "Unterminated string");
+        None
+    }
+
+    /// Lex a `${...}` expression embedded in a string. The `$` has been consumed. The
+    /// text lexed so far is moved into `parts`, followed by the tokens of the
+    /// expression.
+    fn interpolation(&mut self, parts: &mut Vec<StringPart>, s: &mut String) {
+        let (start, start_offset) = self.previous_char();
+        self.advance();
+        let expr_start = self.location;
+        let expr_offset = self.offset;
 
-            let mut is_float = false;
+        // Find the matching closing brace, skipping over nested braces, strings and
+        // chars
+        let mut depth = 0;
+        let mut in_string = false;
+        let expr_end = loop {
+            let end = self.offset;
+            // Running out here means the string is unterminated, which the caller reports
+            let Some(x) = self.advance() else {
+                return;
+            };
+            match x {
+                '\n' => self.newline(),
+                '"' => in_string = !in_string,
+                '\\' if in_string => {
+                    self.advance();
+                }
+                '\'' if !in_string && !self.at_label() => {
+                    // An unterminated char is left for the expression's lexer to report
+                    while let Some(x) = self.peek() {
+                        if x == '\n' {
+                            break;
+                        }
+                        self.advance();
+                        match x {
+                            '\\' => {
+                                self.advance();
+                            }
+                            '\'' => break,
+                            _ => {}
+                        }
+                    }
+                }
+                '{' if !in_string => depth += 1,
+                '}' if !in_string => {
+                    if depth == 0 {
+                        break end;
+                    }
+                    depth -= 1;
+                }
+                _ => {}
+            }
+        };
+
+        // The expression is lexed straight out of the source, sharing the names seen
+        // so far
+        let mut lexer = Lexer::new(
+            Source {
+                text: self.slice(expr_offset, expr_end),
+                base_offset: expr_offset,
+                source_type: self.source.source_type.clone(),
+                file_id: self.source.file_id,
+            },
+            LexMode::Normal,
+        );
+        lexer.location = expr_start;
+        lexer.names = std::mem::take(&mut self.names);
+        let tokens = lexer.lex_tokens();
+        self.names = std::mem::take(&mut lexer.names);
+        if !lexer.diagnostics.is_empty() {
+            self.diagnostics.append(&mut lexer.diagnostics);
+            return;
+        }
+        if tokens.iter().all(|t| t.token_type == TokenType::Newline) {
+            self.error_at(
+                DiagnosticKind::InvalidString,
+                "Empty interpolation",
+                start,
+                start_offset,
+            );
+            return;
+        }
+
+        if !s.is_empty() {
+            parts.push(StringPart::Text(std::mem::take(s)));
+        }
+        parts.push(StringPart::Expr(tokens));
+    }
+
+    /// Lex a character literal such as `'a'` or `'\u{1F600}'`. The opening quote has
+    /// been consumed. The literal must hold exactly one Unicode scalar value.
+    pub fn char_literal(&mut self) -> Option<TokenType> {
+        let value = match self.advance_if_any() {
+            None | Some('\n') => {
+                self.error(
+                    DiagnosticKind::InvalidChar,
+                    "Unterminated character literal",
+                );
+                return None;
+            }
+            Some('\'') => {
+                self.error(DiagnosticKind::InvalidChar, "Empty character literal");
+                return None;
+            }
+            Some('\\') => self.escape(),
+            Some(c) => Some(c),
+        };
+
+        if self.match_char('\'') {
+            return value.map(TokenType::Char);
+        }
+        // Skip to the closing quote so the rest of the line still lexes sensibly
+        while let Some(x) = self.peek() {
+            if x == '\n' {
+                break;
+            }
+            self.advance();
+            if x == '\'' {
+                self.error(
+                    DiagnosticKind::InvalidChar,
+                    "Character literal must hold exactly one character",
+                );
+                return None;
+            }
+        }
+        self.error(
+            DiagnosticKind::InvalidChar,
+            "Unterminated character literal",
+        );
+        None
+    }
 
-            if let Some(&x) = lexer.peek() {
-                if x == '.' {
-                    lexer.advance();
-                    is_float = true;
-                    snum.push('.');
+    /// Lex a raw string such as `r"C:\path"` or `r#"say "hi""#`. The `r` has been
+    /// consumed. Nothing inside a raw string is escaped, and it ends at a quote
+    /// followed by as many `#` as were used to open it.
+    pub fn raw_string(&mut self) -> Option<String> {
+        let mut hashes = 0;
+        while self.peek() == Some('#') {
+            self.advance();
+            hashes += 1;
+        }
+        if self.peek() != Some('"') {
+            self.error(
+                DiagnosticKind::InvalidString,
+                "Expected `\"` to start a raw string",
+            );
+            return None;
+        }
+        self.advance();
 
-                    let num2: String = lexer.get_number();
-                    snum.push_str(&num2);
+        let start = self.offset;
+        while let Some(x) = self.advance() {
+            if x == '\n' {
+                self.newline();
+            }
+            if x == '"' {
+                let end = self.offset - 1;
+                let mut closing = 0;
+                while closing < hashes && self.peek() == Some('#') {
+                    self.advance();
+                    closing += 1;
+                }
+                if closing == hashes {
+                    return Some(self.slice(start, end).to_string());
                 }
             }
-            if is_float {
-                let num: f64 = snum.parse().unwrap();
-                tokens.push(lexer.make_token(TokenType::Float(num)));
-                continue;
-            } else {
-                let num: f64 = snum.parse().unwrap();
-                tokens.push(lexer.make_token(TokenType::Integer(num)));
-                continue;
+        }
+        self.error(
+            DiagnosticKind::UnterminatedString,
+            "Unterminated raw string",
+        );
+        None
+    }
+
+    /// Decode the escape sequence following a backslash. Reports an error and
+    /// returns `None` if the sequence isn't recognised.
+    fn escape(&mut self) -> Option<char> {
+        let start = self.previous_char();
+        let Some(c) = self.advance() else {
+            self.escape_error(start, "Unterminated escape sequence");
+            return None;
+        };
+        let decoded = match c {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            '\\' => '\\',
+            '"' => '"',
+            '\'' => '\'',
+            '$' => '$',
+            'u' => return self.unicode_escape(start),
+            '\n' => {
+                self.newline();
+                self.escape_error(start, "Unknown escape sequence `\\` followed by a newline");
+                return None;
+            }
+            _ => {
+                self.escape_error(start, &format!("Unknown escape sequence `\\{c}`"));
+                return None;
             }
+        };
+        Some(decoded)
+    }
+
+    /// Decode a `\u{...}` escape. The `\u` has been consumed.
+    fn unicode_escape(&mut self, start: (Location, usize)) -> Option<char> {
+        if self.peek() != Some('{') {
+            self.escape_error(start, "Expected `{` after `\\u`");
+            return None;
         }
+        self.advance();
 
-        if c.is_alphabetic() {
-            let mut ident = c.to_string();
-            lexer.advance();
-            while let Some(&x) = lexer.peek() {
-                if x.is_alphanumeric() {
-                    ident.push(x);
-                    lexer.advance();
-                } else {
-                    break;
-                }
-            }
-            let tok = match ident.as_str() {
-                // Statements
-                "let" => lexer.make_token(TokenType::Let),
-                "func" => lexer.make_token(TokenType::Func),
-                "print" => lexer.make_token(TokenType::Print),
-                // Datatypes
-                "int" => lexer.make_token(TokenType::DataType(BaseType::Integer)),
-                // And if all else fails: identifier
-                _ => lexer.make_token(TokenType::Identifier(ident)),
-            };
-            tokens.push(tok);
-            //lexer.advance();
-            continue;
+        let hex_start = self.offset;
+        while let Some(x) = self.peek() {
+            if x == '}' || x == '"' || x == '\n' {
+                break;
+            }
+            self.advance();
+        }
+        let hex = self.slice(hex_start, self.offset);
+        if self.peek() != Some('}') {
+            self.escape_error(start, "Unterminated unicode escape");
+            return None;
+        }
+        self.advance();
+
+        if hex.is_empty() || hex.len() > 6 {
+            self.escape_error(start, "Unicode escape must have between 1 and 6 hex digits");
+            return None;
+        }
+        let code_point = hex
+            .chars()
+            .all(|x| x.is_ascii_hexdigit())
+            .then(|| u32::from_str_radix(hex, 16).ok())
+            .flatten();
+        match code_point.and_then(char::from_u32) {
+            Some(c) => Some(c),
+            None => {
+                self.escape_error(start, &format!("Invalid unicode escape `\\u{{{hex}}}`"));
+                None
+            }
+        }
+    }
+
+    fn escape_error(&mut self, (location, offset): (Location, usize), message: &str) {
+        self.error_at(DiagnosticKind::InvalidEscape, message, location, offset);
+    }
+
+    /// Lex tokens until the source runs out. The `EOF` token isn't included so the
+    /// same loop can be used for expressions embedded in strings.
+    fn lex_tokens(&mut self) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        while let Some(token) = self.next_token() {
+            tokens.push(token);
+        }
+        tokens
+    }
+
+    /// Lex the next token, or return `None` once the source runs out. In lossless
+    /// mode, trivia up to the next token trails this one unless it ends the line.
+    fn next_token(&mut self) -> Option<Token> {
+        while let Some(kind) = self.trivia() {
+            if self.mode == LexMode::Lossless {
+                let trivia = self.trivia_text(kind);
+                self.leading_trivia.push(trivia);
+            }
+        }
+        self.start_token();
+        let c = self.advance_if_any()?;
+        let token_type = self.scan(c);
+        let mut token = self.make_token(token_type);
+
+        if let Some(token_trivia) = token.trivia.as_mut() {
+            if token.token_type != TokenType::Newline {
+                while let Some(kind) = self.trivia() {
+                    token_trivia.trailing.push(self.trivia_text(kind));
+                }
+            }
+        }
+        Some(token)
+    }
+
+    /// Consume the next character unless the source has run out
+    fn advance_if_any(&mut self) -> Option<char> {
+        self.peek()?;
+        self.advance()
+    }
+
+    /// Work out the type of the token starting with `c`, which has been consumed
+    fn scan(&mut self, c: char) -> TokenType {
+        if c.is_ascii_digit() {
+            return self.number(c).unwrap_or(TokenType::Error);
+        }
+
+        if c.is_alphabetic() || c == '_' {
+            if c == 'r' && matches!(self.peek(), Some('"') | Some('#')) {
+                return self.raw_string().map_or(TokenType::Error, TokenType::Text);
+            }
+            return self.identifier();
         }
 
-        lexer.advance();
-        let token_type = match c {
+        match c {
             '[' => TokenType::LBracket,
             ']' => TokenType::RBracket,
             '(' => TokenType::LParen,
             ')' => TokenType::RParen,
-            '!' => TokenType::Bang,
             '{' => TokenType::LBrace,
             '}' => TokenType::RBrace,
-            '.' => TokenType::Dot,
+            '.' => {
+                if self.match_char('.') {
+                    if self.match_char('=') {
+                        TokenType::DotDotEqual
+                    } else {
+                        TokenType::DotDot
+                    }
+                } else {
+                    TokenType::Dot
+                }
+            }
             ',' => TokenType::Comma,
             ';' => TokenType::SemiColon,
             ':' => TokenType::Colon,
+            '!' => {
+                if self.match_char('=') {
+                    TokenType::NotEqual
+                } else {
+                    TokenType::Bang
+                }
+            }
             '=' => {
-                if *lexer.peek().unwrap_or(&'\0') == '=' {
-                    lexer.advance();
+                if self.match_char('=') {
                     TokenType::Equal
+                } else if self.match_char('>') {
+                    TokenType::FatArrow
                 } else {
                     TokenType::Assign
                 }
             }
-            '+' => TokenType::Plus,
-            '-' => TokenType::Minus,
-            '*' => TokenType::Star,
-            '/' => {
-                if let Some(&x) = lexer.peek() {
-                    match x {
-                        '/' => {
-                            lexer.single_line_comment();
-                            continue;
-                        }
-                        '*' => {
-                            lexer.multi_line_comment();
-                            continue;
-                        }
-                        _ => {
-                            lexer.make_token(TokenType::Slash);
-                        }
+            '+' => {
+                if self.match_char('=') {
+                    TokenType::PlusAssign
+                } else {
+                    TokenType::Plus
+                }
+            }
+            '-' => {
+                if self.match_char('>') {
+                    TokenType::Arrow
+                } else if self.match_char('=') {
+                    TokenType::MinusAssign
+                } else {
+                    TokenType::Minus
+                }
+            }
+            '*' => {
+                if self.match_char('*') {
+                    TokenType::StarStar
+                } else if self.match_char('=') {
+                    TokenType::StarAssign
+                } else {
+                    TokenType::Star
+                }
+            }
+            // Plain comments are trivia, so only doc comments get here
+            '/' => match self.peek() {
+                Some('/') => {
+                    self.single_line_comment();
+                    line_doc_text(self.lexeme())
+                }
+                Some('*') => {
+                    if self.multi_line_comment() {
+                        block_doc_text(self.lexeme())
+                    } else {
+                        TokenType::Error
                     }
                 }
-                TokenType::Slash
+                Some('=') => {
+                    self.advance();
+                    TokenType::SlashAssign
+                }
+                _ => TokenType::Slash,
+            },
+            '%' => {
+                if self.match_char('=') {
+                    TokenType::PercentAssign
+                } else {
+                    TokenType::Percent
+                }
+            }
+            '<' => {
+                if self.match_char('=') {
+                    TokenType::LessEqual
+                } else if self.match_char('<') {
+                    TokenType::ShiftLeft
+                } else {
+                    TokenType::LessThan
+                }
+            }
+            '>' => {
+                if self.match_char('=') {
+                    TokenType::GreaterEqual
+                } else if self.match_char('>') {
+                    TokenType::ShiftRight
+                } else {
+                    TokenType::GreaterThan
+                }
+            }
+            '&' => {
+                if self.match_char('&') {
+                    TokenType::AndAnd
+                } else {
+                    TokenType::Ampersand
+                }
+            }
+            '|' => {
+                if self.match_char('|') {
+                    TokenType::OrOr
+                } else if self.match_char('>') {
+                    TokenType::PipeGreater
+                } else {
+                    TokenType::Pipe
+                }
             }
-            '%' => TokenType::Percent,
-            '<' => TokenType::LessThan,
-            '>' => TokenType::GreaterThan,
-            '&' => TokenType::Ampersand,
-            '|' => TokenType::Pipe,
             '^' => TokenType::Caret,
+            '~' => {
+                if self.match_char('/') {
+                    TokenType::TildeSlash
+                } else {
+                    TokenType::Tilde
+                }
+            }
             '#' => TokenType::Hash,
             '@' => TokenType::At,
             '?' => TokenType::Question,
             '\n' => {
-                lexer.newline();
+                self.newline();
                 TokenType::Newline
             }
             '$' => TokenType::Dollar,
-            '"' => {
-                let mut s = String::new();
-                while let Some(&x) = lexer.peek() {
-                    if x == '"' {
-                        break;
-                    }
-                    s.push(x);
-                    lexer.advance();
+            '"' => self.string().unwrap_or(TokenType::Error),
+            '\'' => {
+                if self.at_label() {
+                    self.label()
+                } else {
+                    self.char_literal().unwrap_or(TokenType::Error)
                 }
-                lexer.advance();
-                TokenType::Text(s)
             }
             _ => {
-                let err_msg = format!("Unexpected character: {c}");
-                lexer.report_error(&err_msg);
-                continue;
+                let err_msg = format!("Unexpected character `{c}`");
+                self.error(DiagnosticKind::UnexpectedCharacter, &err_msg);
+                TokenType::Error
             }
-        };
-        tokens.push(lexer.make_token(token_type));
+        }
+    }
+}
+
+/// Pulls tokens out of the source one at a time, ending with `EOF`. Anything that
+/// can't be lexed comes out as an error in place of its token, and lexing carries
+/// on after it.
+pub struct Tokens<'a> {
+    lexer: Lexer<'a>,
+    // Tokens and errors from a single step of the lexer, waiting to be handed out
+    pending: VecDeque<Result<Token>>,
+    finished: bool,
+}
+
+impl Iterator for Tokens<'_> {
+    type Item = Result<Token>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() {
+            if self.finished {
+                return None;
+            }
+            let token = self.lexer.next_token().unwrap_or_else(|| {
+                self.finished = true;
+                self.lexer.start_token();
+                self.lexer.make_token(TokenType::EOF)
+            });
+            if self.lexer.diagnostics.is_empty() {
+                return Some(Ok(token));
+            }
+            for diagnostic in self.lexer.diagnostics.drain(..) {
+                self.pending
+                    .push_back(Err(Diagnostics(vec![diagnostic]).into()));
+            }
+            if token.token_type != TokenType::Error {
+                self.pending.push_back(Ok(token));
+            }
+        }
+        self.pending.pop_front()
     }
-    if lexer.error_mode {
-        return Err(lexer.make_error("Lexer error"));
+}
+
+/// Look up the token for a reserved word
+fn keyword(ident: &str) -> Option<TokenType> {
+    let token_type = match ident {
+        // Statements
+        "let" => TokenType::Let,
+        "func" => TokenType::Func,
+        "struct" => TokenType::Struct,
+        "enum" => TokenType::Enum,
+        "print" => TokenType::Print,
+        // Control flow
+        "if" => TokenType::If,
+        "else" => TokenType::Else,
+        "while" => TokenType::While,
+        "for" => TokenType::For,
+        "in" => TokenType::In,
+        "return" => TokenType::Return,
+        "break" => TokenType::Break,
+        "continue" => TokenType::Continue,
+        "loop" => TokenType::Loop,
+        // Logical operators
+        "and" => TokenType::And,
+        "or" => TokenType::Or,
+        "not" => TokenType::Not,
+        // Values
+        "true" => TokenType::Boolean(true),
+        "false" => TokenType::Boolean(false),
+        "nil" => TokenType::Nil,
+        // Datatypes
+        "int" => TokenType::DataType(BaseType::Integer),
+        "float" => TokenType::DataType(BaseType::Float),
+        "text" => TokenType::DataType(BaseType::Text),
+        "bool" => TokenType::DataType(BaseType::Boolean),
+        "char" => TokenType::DataType(BaseType::Char),
+        _ => return None,
+    };
+    Some(token_type)
+}
+
+/// Turn the pieces of a string literal into a token. Strings without any `${...}`
+/// are plain `Text`.
+fn finish_string(mut parts: Vec<StringPart>, s: String) -> TokenType {
+    if parts.is_empty() {
+        return TokenType::Text(s);
+    }
+    if !s.is_empty() {
+        parts.push(StringPart::Text(s));
     }
+    TokenType::Interpolated(parts)
+}
+
+/// Strip the `///` marker and the space that usually follows it
+fn line_doc_text(lexeme: &str) -> TokenType {
+    let text = lexeme.trim_start_matches('/').trim_end();
+    TokenType::DocComment(text.strip_prefix(' ').unwrap_or(text).to_string())
+}
+
+/// Strip the `/**` and `*/` markers along with any leading `*` on each line
+fn block_doc_text(lexeme: &str) -> TokenType {
+    let body = &lexeme[3..lexeme.len() - 2];
+    let lines: Vec<&str> = body
+        .lines()
+        .map(|line| {
+            let line = line.trim_start();
+            let line = line.strip_prefix('*').unwrap_or(line);
+            line.strip_prefix(' ').unwrap_or(line).trim_end()
+        })
+        .collect();
+    let start = lines
+        .iter()
+        .position(|l| !l.is_empty())
+        .unwrap_or(lines.len());
+    let end = lines
+        .iter()
+        .rposition(|l| !l.is_empty())
+        .map_or(start, |i| i + 1);
+    TokenType::DocComment(lines[start..end].join("\n"))
+}
+
+pub fn lex(code: &str, source_type: SourceType) -> Result<Vec<Token>> {
+    into_result(lex_with_diagnostics(code, source_type, 0, LexMode::Normal))
+}
+
+/// Lex keeping whitespace and comments as trivia on the tokens
+pub fn lex_lossless(code: &str, source_type: SourceType) -> Result<Vec<Token>> {
+    into_result(lex_with_diagnostics(
+        code,
+        source_type,
+        0,
+        LexMode::Lossless,
+    ))
+}
+
+/// Lex a file that has been loaded into a source map. The tokens' spans carry the
+/// file's id.
+pub fn lex_file(sources: &SourceMap, file_id: FileId, mode: LexMode) -> Result<Vec<Token>> {
+    let file = sources
+        .get(file_id)
+        .ok_or_else(|| anyhow!("No source file with id {file_id}"))?;
+    into_result(lex_with_diagnostics(
+        &file.source,
+        file.source_type.clone(),
+        file_id,
+        mode,
+    ))
+}
+
+/// Lex the whole of `code`, collecting every problem found rather than stopping at
+/// the first. Source that can't be lexed becomes an `Error` token so the rest of the
+/// token stream is still usable.
+pub fn lex_with_diagnostics(
+    code: &str,
+    source_type: SourceType,
+    file_id: FileId,
+    mode: LexMode,
+) -> (Vec<Token>, Vec<Diagnostic>) {
+    let mut lexer = Lexer::new(
+        Source {
+            text: code,
+            base_offset: 0,
+            source_type,
+            file_id,
+        },
+        mode,
+    );
+
+    let mut tokens = lexer.lex_tokens();
+    lexer.start_token();
     tokens.push(lexer.make_token(TokenType::EOF));
+    (tokens, lexer.diagnostics)
+}
+
+/// Lex `code` lazily, handing out each token as it's asked for
+pub fn lex_stream(
+    code: &str,
+    source_type: SourceType,
+    file_id: FileId,
+    mode: LexMode,
+) -> Tokens<'_> {
+    Tokens {
+        lexer: Lexer::new(
+            Source {
+                text: code,
+                base_offset: 0,
+                source_type,
+                file_id,
+            },
+            mode,
+        ),
+        pending: VecDeque::new(),
+        finished: false,
+    }
+}
+
+fn into_result((tokens, diagnostics): (Vec<Token>, Vec<Diagnostic>)) -> Result<Vec<Token>> {
+    if !diagnostics.is_empty() {
+        return Err(Diagnostics(diagnostics).into());
+    }
     Ok(tokens)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tokens::tokens_to_text;
+
+    fn lex_text(code: &str) -> Result<String> {
+        let tokens = lex(code, SourceType::Test)?;
+        match &tokens[0].token_type {
+            TokenType::Text(s) => Ok(s.clone()),
+            other => Err(anyhow!("Expected text, found {:?}", other)),
+        }
+    }
+
+    #[test]
+    fn test_string_escapes() {
+        assert_eq!(lex_text(r#""a\tb\nc""#).unwrap(), "a\tb\nc");
+        assert_eq!(lex_text(r#""say \"hi\"""#).unwrap(), "say \"hi\"");
+        assert_eq!(lex_text(r#""back\\slash""#).unwrap(), "back\\slash");
+        assert_eq!(lex_text(r#""\u{48}\u{1F600}""#).unwrap(), "H\u{1F600}");
+        assert_eq!(lex_text(r#""""#).unwrap(), "");
+        assert!(lex_text(r#""\q""#).is_err());
+        assert!(lex_text(r#""\u{110000}""#).is_err());
+        assert!(lex_text(r#""\u{+41}""#).is_err());
+    }
+
+    #[test]
+    fn test_chars() {
+        let types: Vec<_> = lex(r"'a' '\n' '\'' '\u{1F600}' 'é'", SourceType::Test)
+            .unwrap()
+            .into_iter()
+            .map(|t| t.token_type)
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Char('a'),
+                TokenType::Char('\n'),
+                TokenType::Char('\''),
+                TokenType::Char('\u{1F600}'),
+                TokenType::Char('é'),
+                TokenType::EOF,
+            ]
+        );
+        assert!(lex("''", SourceType::Test).is_err());
+        assert!(lex("'ab'", SourceType::Test).is_err());
+        assert!(lex("'1", SourceType::Test).is_err());
+        assert!(lex(r"'\u{D800}'", SourceType::Test).is_err());
+
+        let (tokens, diagnostics) =
+            lex_with_diagnostics("'ab' + 1", SourceType::Test, 0, LexMode::Normal);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].kind, DiagnosticKind::InvalidChar);
+        assert_eq!(tokens[1].token_type, TokenType::Plus);
+    }
+
+    #[test]
+    fn test_labels() {
+        let types: Vec<_> = lex("'outer: loop { break 'outer } 'x' 'y", SourceType::Test)
+            .unwrap()
+            .into_iter()
+            .map(|t| t.token_type)
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Label("outer".into()),
+                TokenType::Colon,
+                TokenType::Loop,
+                TokenType::LBrace,
+                TokenType::Break,
+                TokenType::Label("outer".into()),
+                TokenType::RBrace,
+                TokenType::Char('x'),
+                TokenType::Label("y".into()),
+                TokenType::EOF,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_raw_strings() {
+        assert_eq!(lex_text(r#"r"C:\temp\n""#).unwrap(), r"C:\temp\n");
+        assert_eq!(lex_text(r###"r#"say "hi""#"###).unwrap(), r#"say "hi""#);
+        assert!(lex_text(r###"r#"never closed""###).is_err());
+    }
+
+    #[test]
+    fn test_multi_line_strings() {
+        let code = "\"\"\"\nfirst\n  \"second\"\\t\n\"\"\"\nprint 1";
+        assert_eq!(lex_text(code).unwrap(), "first\n  \"second\"\t\n");
+
+        let tokens = lex(code, SourceType::Test).unwrap();
+        assert_eq!(tokens[1].token_type, TokenType::Newline);
+        assert_eq!(tokens[2].location.line, 5);
+    }
+
+    #[test]
+    fn test_unterminated_string() {
+        assert!(lex("let s = \"abc", SourceType::Test).is_err());
+        assert!(lex("let s = \"\"\"abc\"\"", SourceType::Test).is_err());
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let tokens = lex(r#""Hello ${name}, total ${a + b}""#, SourceType::Test).unwrap();
+        let TokenType::Interpolated(parts) = &tokens[0].token_type else {
+            panic!("Expected an interpolated string, found {:?}", tokens[0]);
+        };
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[0], StringPart::Text("Hello ".to_string()));
+        assert_eq!(parts[2], StringPart::Text(", total ".to_string()));
+        let StringPart::Expr(expr) = &parts[3] else {
+            panic!("Expected an expression, found {:?}", parts[3]);
+        };
+        let types: Vec<_> = expr.iter().map(|t| t.token_type.clone()).collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Identifier("a".into()),
+                TokenType::Plus,
+                TokenType::Identifier("b".into()),
+            ]
+        );
+
+        assert_eq!(
+            lex_text(r#""costs \${5} or $5""#).unwrap(),
+            "costs ${5} or $5"
+        );
+        assert!(lex(r#""${}""#, SourceType::Test).is_err());
+        assert!(lex(r#""${1 + 2""#, SourceType::Test).is_err());
+
+        // Braces and quotes in chars don't end the expression or start a string
+        for code in [r#""a${'}'}b""#, r#""a${'"'}b""#, r#""a${'\''}b""#] {
+            let tokens = lex(code, SourceType::Test).unwrap();
+            let TokenType::Interpolated(parts) = &tokens[0].token_type else {
+                panic!("Expected an interpolated string, found {:?}", tokens[0]);
+            };
+            assert_eq!(parts.len(), 3, "{code}");
+            assert_eq!(parts[2], StringPart::Text("b".to_string()));
+        }
+    }
+
+    #[test]
+    fn test_keywords() {
+        let tokens = lex(
+            "if true and not false or nil else while for x in return break continue loop struct enum",
+            SourceType::Test,
+        )
+        .unwrap();
+        let types: Vec<_> = tokens.into_iter().map(|t| t.token_type).collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::If,
+                TokenType::Boolean(true),
+                TokenType::And,
+                TokenType::Not,
+                TokenType::Boolean(false),
+                TokenType::Or,
+                TokenType::Nil,
+                TokenType::Else,
+                TokenType::While,
+                TokenType::For,
+                TokenType::Identifier("x".into()),
+                TokenType::In,
+                TokenType::Return,
+                TokenType::Break,
+                TokenType::Continue,
+                TokenType::Loop,
+                TokenType::Struct,
+                TokenType::Enum,
+                TokenType::EOF,
+            ]
+        );
+
+        let tokens = lex("int float text bool", SourceType::Test).unwrap();
+        let types: Vec<_> = tokens.into_iter().map(|t| t.token_type).collect();
+        assert_eq!(
+            types[..4],
+            [
+                TokenType::DataType(BaseType::Integer),
+                TokenType::DataType(BaseType::Float),
+                TokenType::DataType(BaseType::Text),
+                TokenType::DataType(BaseType::Boolean),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_identifiers() {
+        let tokens = lex("_tmp my_var x2 iffy r", SourceType::Test).unwrap();
+        let names: Vec<_> = tokens
+            .into_iter()
+            .filter_map(|t| match t.token_type {
+                TokenType::Identifier(name) => Some(name),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(names, ["_tmp", "my_var", "x2", "iffy", "r"].map(Rc::from));
+
+        // Every use of a name shares the same string, even inside interpolations
+        let tokens = lex("total = total + \"${total}\"", SourceType::Test).unwrap();
+        let TokenType::Identifier(first) = &tokens[0].token_type else {
+            panic!("Expected an identifier");
+        };
+        let TokenType::Interpolated(parts) = &tokens[4].token_type else {
+            panic!("Expected an interpolated string");
+        };
+        let StringPart::Expr(embedded) = &parts[0] else {
+            panic!("Expected an expression");
+        };
+        for token in [&tokens[2], &embedded[0]] {
+            let TokenType::Identifier(name) = &token.token_type else {
+                panic!("Expected an identifier");
+            };
+            assert!(Rc::ptr_eq(first, name));
+        }
+    }
+
+    #[test]
+    fn test_operators() {
+        let code = "!= <= >= && || -> => .. ..= |> ** += -= *= /= %= << >> ~/ ~";
+        let tokens = lex(code, SourceType::Test).unwrap();
+        let types: Vec<_> = tokens.into_iter().map(|t| t.token_type).collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::NotEqual,
+                TokenType::LessEqual,
+                TokenType::GreaterEqual,
+                TokenType::AndAnd,
+                TokenType::OrOr,
+                TokenType::Arrow,
+                TokenType::FatArrow,
+                TokenType::DotDot,
+                TokenType::DotDotEqual,
+                TokenType::PipeGreater,
+                TokenType::StarStar,
+                TokenType::PlusAssign,
+                TokenType::MinusAssign,
+                TokenType::StarAssign,
+                TokenType::SlashAssign,
+                TokenType::PercentAssign,
+                TokenType::ShiftLeft,
+                TokenType::ShiftRight,
+                TokenType::TildeSlash,
+                TokenType::Tilde,
+                TokenType::EOF,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_maximal_munch() {
+        let types = |code: &str| -> Vec<TokenType> {
+            let tokens = lex(code, SourceType::Test).unwrap();
+            tokens.into_iter().map(|t| t.token_type).collect()
+        };
+        assert_eq!(
+            types("0..10"),
+            vec![
+                TokenType::Integer(0),
+                TokenType::DotDot,
+                TokenType::Integer(10),
+                TokenType::EOF,
+            ]
+        );
+        assert_eq!(
+            types("a...b"),
+            vec![
+                TokenType::Identifier("a".into()),
+                TokenType::DotDot,
+                TokenType::Dot,
+                TokenType::Identifier("b".into()),
+                TokenType::EOF,
+            ]
+        );
+        assert_eq!(
+            types("!==>>="),
+            vec![
+                TokenType::NotEqual,
+                TokenType::FatArrow,
+                TokenType::GreaterEqual,
+                TokenType::EOF,
+            ]
+        );
+        assert_eq!(
+            types("x-->y"),
+            vec![
+                TokenType::Identifier("x".into()),
+                TokenType::Minus,
+                TokenType::Arrow,
+                TokenType::Identifier("y".into()),
+                TokenType::EOF,
+            ]
+        );
+        assert_eq!(types("1.5")[0], TokenType::Float(1.5));
+    }
+
+    #[test]
+    fn test_numbers() {
+        let number = |code: &str| -> Result<TokenType> {
+            let tokens = lex(code, SourceType::Test)?;
+            Ok(tokens[0].token_type.clone())
+        };
+        assert_eq!(number("42").unwrap(), TokenType::Integer(42));
+        assert_eq!(number("1_000_000").unwrap(), TokenType::Integer(1_000_000));
+        assert_eq!(number("0xFF").unwrap(), TokenType::Integer(255));
+        assert_eq!(
+            number("0xdead_beef").unwrap(),
+            TokenType::Integer(0xdead_beef)
+        );
+        assert_eq!(number("0b1010").unwrap(), TokenType::Integer(10));
+        assert_eq!(number("0o17").unwrap(), TokenType::Integer(15));
+        assert_eq!(
+            number("9007199254740993").unwrap(),
+            TokenType::Integer(9_007_199_254_740_993)
+        );
+        assert_eq!(number("1.5").unwrap(), TokenType::Float(1.5));
+        assert_eq!(number("1.5e-3").unwrap(), TokenType::Float(1.5e-3));
+        assert_eq!(number("2E+2").unwrap(), TokenType::Float(200.0));
+        assert_eq!(number("3e2").unwrap(), TokenType::Float(300.0));
+        assert_eq!(number("2f").unwrap(), TokenType::Float(2.0));
+        assert_eq!(number("7i").unwrap(), TokenType::Integer(7));
+        assert_eq!(number("0x1Fi").unwrap(), TokenType::Integer(31));
+
+        assert!(number("9223372036854775808").is_err());
+        assert!(number("0x").is_err());
+        assert!(number("0b102").is_err());
+        assert!(number("1.5i").is_err());
+        assert!(number("12abc").is_err());
+        assert!(number("1e999").is_err());
+    }
+
+    #[test]
+    fn test_spans() {
+        let code = "let pi = 3.14\nprint \"π is ${pi}\"";
+        let tokens = lex(code, SourceType::Test).unwrap();
+        let spans: Vec<_> = tokens
+            .iter()
+            .map(|t| &code[t.span.start_byte..t.span.end_byte])
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                "let",
+                "pi",
+                "=",
+                "3.14",
+                "\n",
+                "print",
+                "\"π is ${pi}\"",
+                ""
+            ]
+        );
+        assert_eq!(tokens[5].location, Location { line: 2, column: 0 });
+        assert_eq!(tokens[6].location, Location { line: 2, column: 6 });
+
+        let TokenType::Interpolated(parts) = &tokens[6].token_type else {
+            panic!("Expected an interpolated string");
+        };
+        let StringPart::Expr(expr) = &parts[1] else {
+            panic!("Expected an expression");
+        };
+        assert_eq!(&code[expr[0].span.start_byte..expr[0].span.end_byte], "pi");
+        assert_eq!(
+            expr[0].location,
+            Location {
+                line: 2,
+                column: 14
+            }
+        );
+    }
+
+    #[test]
+    fn test_lossless_round_trip() {
+        let code = "// header\r\nlet  x = 0xFF_i // trailing\n\t/* block\n comment */ print \"a\\n${x +  1}\"\n\n  r#\"raw\"#   ";
+        let tokens = lex_lossless(code, SourceType::Test).unwrap();
+        assert_eq!(tokens_to_text(&tokens), code);
+
+        // Comments after a token on the same line trail it, the rest lead the next token
+        let trivia = |i: usize| tokens[i].trivia.as_ref().unwrap();
+        assert_eq!(trivia(0).leading[0].kind, TriviaKind::LineComment);
+        assert_eq!(trivia(0).text, "\n");
+        assert_eq!(trivia(1).text, "let");
+        assert_eq!(trivia(4).text, "0xFF_i");
+        assert_eq!(
+            trivia(4).trailing,
+            vec![
+                Trivia {
+                    kind: TriviaKind::Whitespace,
+                    text: " ".to_string()
+                },
+                Trivia {
+                    kind: TriviaKind::LineComment,
+                    text: "// trailing".to_string()
+                },
+            ]
+        );
+        assert_eq!(trivia(6).leading.len(), 3);
+        assert_eq!(trivia(6).leading[1].kind, TriviaKind::BlockComment);
+
+        // Normal mode doesn't keep any of it
+        let tokens = lex(code, SourceType::Test).unwrap();
+        assert!(tokens.iter().all(|t| t.trivia.is_none()));
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let code = "let a = 1 # 2\nlet b = 0x\nlet c = \"bad \\q escape\" @@ 3\n/* never closed";
+        let (tokens, diagnostics) =
+            lex_with_diagnostics(code, SourceType::Test, 0, LexMode::Normal);
+        let found: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.kind, d.location.line, d.location.column))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (DiagnosticKind::InvalidNumber, 2, 8),
+                (DiagnosticKind::InvalidEscape, 3, 13),
+                (DiagnosticKind::UnterminatedComment, 4, 0),
+            ]
+        );
+        let span = diagnostics[1].span;
+        assert_eq!(&code[span.start_byte..span.end_byte], "\\q");
+
+        // The bad number becomes an error token and lexing carries on after it
+        let types: Vec<_> = tokens.iter().map(|t| t.token_type.clone()).collect();
+        assert_eq!(types[10], TokenType::Error);
+        assert_eq!(types[11], TokenType::Newline);
+        assert_eq!(types[15], TokenType::Text("bad  escape".to_string()));
+        assert_eq!(types[18], TokenType::Integer(3));
+        assert_eq!(types.last(), Some(&TokenType::EOF));
+
+        let (tokens, diagnostics) =
+            lex_with_diagnostics("print ¬ \"open", SourceType::Test, 0, LexMode::Normal);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].kind, DiagnosticKind::UnexpectedCharacter);
+        assert_eq!(diagnostics[0].location.column, 6);
+        assert_eq!(diagnostics[1].kind, DiagnosticKind::UnterminatedString);
+        assert_eq!(diagnostics[1].location.column, 8);
+        assert_eq!(tokens[1].token_type, TokenType::Error);
+        assert_eq!(tokens[2].token_type, TokenType::Error);
+
+        let err = lex("1 ¬ 2 ¬", SourceType::Test).unwrap_err();
+        let Some(Diagnostics(diagnostics)) = err.downcast_ref::<Diagnostics>() else {
+            panic!("Expected diagnostics");
+        };
+        assert_eq!(diagnostics.len(), 2);
+    }
+
+    #[test]
+    fn test_comments() {
+        let code = "1 /* outer /* inner */ still outer */ 2 // line\n//// not a doc\n3";
+        let types: Vec<_> = lex(code, SourceType::Test)
+            .unwrap()
+            .into_iter()
+            .map(|t| t.token_type)
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Integer(1),
+                TokenType::Integer(2),
+                TokenType::Newline,
+                TokenType::Newline,
+                TokenType::Integer(3),
+                TokenType::EOF,
+            ]
+        );
+        assert!(lex("1 /* /* */ 2", SourceType::Test).is_err());
+        assert!(
+            lex("/**/ /*** banner ***/", SourceType::Test)
+                .unwrap()
+                .len()
+                == 1
+        );
+    }
+
+    #[test]
+    fn test_doc_comments() {
+        let code = "/// Adds one\n///    indented\n/**\n * Block\n * doc\n */ /** inline */";
+        let docs: Vec<_> = lex(code, SourceType::Test)
+            .unwrap()
+            .into_iter()
+            .filter_map(|t| match t.token_type {
+                TokenType::DocComment(text) => Some(text),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            docs,
+            vec!["Adds one", "   indented", "Block\ndoc", "inline"]
+        );
+        assert!(lex("/** never closed", SourceType::Test).is_err());
+    }
+
+    #[test]
+    fn test_stream() {
+        let code = "let a = 0x1F // note\nprint \"${a}\" ¬ 2";
+        let streamed: Vec<_> = lex_stream(code, SourceType::Test, 0, LexMode::Lossless).collect();
+        let (tokens, diagnostics) =
+            lex_with_diagnostics(code, SourceType::Test, 0, LexMode::Lossless);
+
+        // The bad character comes out as an error where its token would have been
+        assert_eq!(streamed.len(), tokens.len());
+        let error = streamed[7].as_ref().unwrap_err();
+        assert_eq!(error.downcast_ref::<Diagnostics>().unwrap().0, diagnostics);
+        for (streamed, token) in streamed
+            .iter()
+            .zip(&tokens)
+            .filter(|(_, t)| t.token_type != TokenType::Error)
+        {
+            assert_eq!(streamed.as_ref().unwrap(), token);
+        }
+        assert_eq!(tokens_to_text(&tokens), code);
+    }
+}
@@ -7,9 +7,10 @@ mod arena;
 pub mod ast;
 pub mod datatypes;
 mod debug;
-mod errors;
+pub mod errors;
 pub mod generator;
 pub mod parse;
+pub mod sourcemap;
 mod symbols;
 
 pub struct Deferable<F: FnOnce()>(Option<F>);
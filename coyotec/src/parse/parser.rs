@@ -2,11 +2,14 @@
 
 use crate::ast::tree::ValueType;
 use crate::ast::tree::ValueType::*;
-use crate::datatypes::datatype::DataType;
+use crate::datatypes::datatype::{DataType, TypeAnnotation};
+use crate::datatypes::registry::{EnumType, FunctionType, StructType, TypeRegistry};
+use crate::errors::{Diagnostic, DiagnosticKind, Diagnostics};
 /// The parser takes a vector of tokens from the lexer and builds the AST
 ///
 /// The parser is a recursive descent parser that builds the AST from the tokens
-use crate::tokens::{BaseType, Location, Token, TokenType};
+use crate::lexer::Tokens;
+use crate::tokens::{BaseType, Location, Span, StringPart, Token, TokenType};
 use std::cmp::PartialEq;
 use std::collections::HashMap;
 
@@ -16,78 +19,139 @@ use std::slice::Iter;
 use crate::allocator::Registers;
 use crate::ast::node::NodeType::*;
 use crate::ast::node::UnOp::Neg;
-use crate::ast::node::{BinOp, Node, NodeType, UnOp};
+use crate::ast::node::{BinOp, FunctionDef, Node, NodeType, StructLiteral, UnOp, Variant};
 use crate::symbols::{Symbol, SymbolTable};
 use crate::tokens::{BaseType::*, TokenType::*};
 use crate::{tokens, Deferable};
 
 const PREVIOUS: usize = 0;
 const CURRENT: usize = 1;
-#[derive(Clone)]
-pub struct Parser {
+pub struct Parser<'a> {
     pub source_code: String,
+    // The tokens pulled from the lexer so far
     pub tokens: Vec<Token>,
+    // Where the rest of the tokens come from, if they're lexed as they're needed
+    stream: Option<Tokens<'a>>,
+    // Problems the lexer found in the tokens pulled so far
+    lex_errors: Vec<Diagnostic>,
     current: usize,            // The current token position being parsed
     symbol_table: SymbolTable, // A map of symbol names to location numbers
     has_error: bool,
+    doc: Option<String>,        // Doc comments waiting for the next declaration
+    loops: Vec<Option<String>>, // The labels of the loops around the current statement
+    returns: Vec<Option<TypeAnnotation>>, // The result types of the functions around it
+    types: TypeRegistry,        // The structs and enums declared so far
+    // Set in conditions, where a `{` after a struct name starts the block instead
+    no_struct_literals: bool,
+    // Names that weren't declared where they were used, and whether that was outside
+    // of every function. They can still be globals declared further on.
+    unresolved: Vec<(Node, bool)>,
 }
 
-impl Parser {
+impl<'a> Parser<'a> {
     pub fn new(tokens: Vec<Token>, source_code: String) -> Self {
         Self {
             // Iterators are used to avoid moving the vector of tokens
             tokens,
+            stream: None,
+            lex_errors: Vec::new(),
             source_code,
             current: 0,
             symbol_table: SymbolTable::new(),
             has_error: false,
+            doc: None,
+            loops: Vec::new(),
+            returns: Vec::new(),
+            types: TypeRegistry::new(),
+            no_struct_literals: false,
+            unresolved: Vec::new(),
         }
     }
 
-    fn current_token(&self) -> Option<Token> {
-        self.tokens.get(self.current).cloned()
+    /// Make a parser that pulls each token from the lexer when it gets to it
+    pub fn from_stream(tokens: Tokens<'a>, source_code: String) -> Self {
+        let mut parser = Self::new(Vec::new(), source_code);
+        parser.stream = Some(tokens);
+        parser
     }
 
-    fn raise_error(&mut self, msg: &str) {
+    /// The token `offset` places after the current one, lexing up to it if needed
+    fn lookahead(&mut self, offset: usize) -> Option<&Token> {
+        let index = self.current + offset;
+        while self.tokens.len() <= index {
+            let Some(stream) = &mut self.stream else {
+                break;
+            };
+            match stream.next() {
+                Some(Ok(token)) => self.tokens.push(token),
+                Some(Err(err)) => self.lex_error(err),
+                None => self.stream = None,
+            }
+        }
+        self.tokens.get(index)
+    }
+
+    /// Keep a problem the lexer found, so that it can be reported once parsing stops
+    fn lex_error(&mut self, err: Error) {
+        match err.downcast::<Diagnostics>() {
+            Ok(Diagnostics(diagnostics)) => self.lex_errors.extend(diagnostics),
+            Err(err) => unreachable!("The lexer only reports diagnostics, found {err}"),
+        }
+    }
+
+    fn current_token(&mut self) -> Option<Token> {
+        self.lookahead(0).cloned()
+    }
+
+    /// Record a problem with the token being parsed and turn it into an error
+    fn syntax_error(&mut self, msg: &str) -> Error {
+        self.has_error = true;
+        self.lookahead(0);
+        let token = self.tokens.get(self.current).or(self.tokens.last());
+        let (location, span) = token.map(|t| (t.location, t.span)).unwrap_or_default();
+        Diagnostics(vec![Diagnostic {
+            kind: DiagnosticKind::InvalidSyntax,
+            message: msg.to_string(),
+            location,
+            span,
+        }])
+        .into()
+    }
+
+    /// Record a problem with the code that `node` was parsed from and turn it into an
+    /// error
+    fn diagnostic(&mut self, kind: DiagnosticKind, msg: &str, node: &Node) -> Error {
         self.has_error = true;
-        let current = self.current;
-        let token = self.tokens[current].clone();
-        let line = self
-            .source_code
-            .lines()
-            .nth(token.location.line - 1 as usize)
-            .unwrap_or("");
-        let line_number = token.location.line;
-        let arrow = format!("{: >1$}", "^", token.location.column + 1 as usize);
-        println!("Line :{line_number} | {msg}");
-        println!("|");
-        println!("| {line}");
-        println!("| {arrow}");
+        let token = match node.token.clone() {
+            Some(token) => token,
+            None => self.current_token().expect("There's a token being parsed"),
+        };
+        Diagnostics(vec![Diagnostic {
+            kind,
+            message: msg.to_string(),
+            location: token.location,
+            span: token.span,
+        }])
+        .into()
     }
 
     pub fn add_tokens(&mut self, tokens: Vec<Token>, source_code: String) {
         self.source_code = source_code;
         self.tokens = tokens;
+        self.stream = None;
         self.current = 0;
     }
 
     /// Advance the token iterator and return the next token. If there are no more tokens
     /// return `None`
     pub fn advance(&mut self) -> Option<Token> {
-        let current = self.current;
-        if current < self.tokens.len() {
-            self.current += 1;
-            return Some(self.tokens[current].clone());
-        }
-        None
+        let token = self.current_token()?;
+        self.current += 1;
+        Some(token)
     }
     /// Peek at the next token without advancing the iterator
     pub fn peek(&mut self) -> Option<Token> {
-        let current = self.current;
-        if current < self.tokens.len() {
-            return Some(self.tokens[current].clone());
-        }
-        None
+        self.current_token()
     }
     /// Parse a `let` statement
     fn parse_let(&mut self) -> Result<Node> {
@@ -96,15 +160,26 @@ impl Parser {
 
         // Create a new node from the `let` token
         let mut node = Node::new(NodeType::Let, self.current_token());
+        node.doc = self.doc.take();
 
         // Tie the identifier to the variable
         let mut identifier = self.new_identifier()?;
+        if self.match_token(TokenType::Colon) {
+            let annotation = self.parse_type()?;
+            identifier.return_type = self.types.base_type(&annotation);
+            identifier.annotation = Some(annotation);
+        }
 
         if self.match_token(TokenType::Assign) {
-            let expr = self.parse_expr(0)?;
+            let mut expr = self.parse_expr(0)?;
+            if let Some(annotation) = &identifier.annotation {
+                self.expect_annotation(annotation, &mut expr, "The initial value")?;
+            }
             identifier.add_child(expr);
         }
 
+        // The variable isn't in scope in its own initialiser
+        self.declare_variable(&identifier);
         node.add_child(identifier);
         Ok(node)
     }
@@ -117,60 +192,915 @@ impl Parser {
             };
             return Ok(data_type);
         }
-        Err(anyhow!("Expected a data type"))
+        Err(self.syntax_error("Expected a data type"))
     }
 
     /// Parse an identifier into a node
     ///
     fn new_identifier(&mut self) -> Result<Node> {
         if let Some(token) = self.peek() {
-            let mut node = if let TokenType::Identifier(name) = token.token_type {
+            let node = if let TokenType::Identifier(name) = token.token_type {
                 Node::new(
-                    NodeType::Ident(Box::from(name), Box::from(NodeType::Undefined)),
+                    NodeType::Ident(Box::new(name.to_string()), Box::from(NodeType::Undefined)),
                     self.current_token(),
                 )
             } else {
-                return Err(anyhow!("Expected identifier after `let`"));
+                return Err(self.syntax_error("Expected an identifier"));
             };
             self.advance();
             Ok(node)
         } else {
-            Err(anyhow!("Expected identifier after `let`"))
+            Err(self.syntax_error("Expected an identifier"))
         }
     }
 
     pub fn parse(&mut self) -> Result<Node> {
         // This is the starting point
         let mut node = Node::new(NodeType::Root, None);
+        self.unresolved.clear();
+        let result = self
+            .parse_statements(&mut node, TokenType::EOF)
+            .and_then(|_| self.resolve_globals());
+
+        // Problems the lexer found come first, since they can be what upset the
+        // parser, so the rest of the code is lexed for them if parsing stopped early
+        if let Some(stream) = self.stream.take() {
+            for err in stream.filter_map(Result::err) {
+                self.lex_error(err);
+            }
+        }
+        if !self.lex_errors.is_empty() {
+            return Err(Diagnostics(std::mem::take(&mut self.lex_errors)).into());
+        }
+        result.map(|_| node)
+    }
+
+    /// Check the names that were used before they were declared. Functions can use
+    /// any global, and the rest of the code can only call functions declared further
+    /// on, since they're made before anything else runs.
+    fn resolve_globals(&mut self) -> Result<()> {
+        for (node, top_level) in std::mem::take(&mut self.unresolved) {
+            let NodeType::Ident(name, _) = &node.node_type else {
+                continue;
+            };
+            let found = match self.symbol_table.global(name) {
+                Some(item) => !top_level || matches!(item.data_type, DataType::Function(_)),
+                None => false,
+            };
+            if !found {
+                let msg = format!("Unknown variable {name}");
+                return Err(self.diagnostic(DiagnosticKind::UnknownVariable, &msg, &node));
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse statements into `parent` until the `end` token, which is left for the
+    /// caller to consume
+    fn parse_statements(&mut self, parent: &mut Node, end: TokenType) -> Result<()> {
         while let Some(token) = self.peek() {
             match token.token_type {
-                TokenType::Let => {
-                    if let Ok(n) = self.parse_let() {
-                        node.add_child(n);
-                    }
-                }
-                TokenType::Print => {
+                ref t if *t == end => break,
+                TokenType::EOF => break,
+                TokenType::DocComment(text) => {
+                    // Consecutive `///` lines make up a single doc comment
                     self.advance();
-                    let expr = self.parse_expr(0)?;
-                    let mut print_node = Node::new(NodeType::Print, Some(token));
-                    print_node.add_child(expr);
-                    node.add_child(print_node);
+                    self.doc = Some(match self.doc.take() {
+                        Some(doc) => format!("{doc}\n{text}"),
+                        None => text,
+                    });
+                    continue;
                 }
-
-                TokenType::Newline => {
+                TokenType::Newline | TokenType::SemiColon => {
                     self.advance();
                     continue;
                 }
-                _ => {
-                    if let Ok(n) = self.parse_expr(0) {
-                        node.add_child(n);
-                    } else {
-                        bail!("Unexpected token {:?}", token.token_type);
+                _ => {}
+            }
+            let statement = self.parse_statement(token)?;
+            parent.add_child(statement);
+            // Doc comments only belong to the declaration right after them
+            self.doc = None;
+            self.end_statement(&end)?;
+        }
+        Ok(())
+    }
+
+    /// Parse the statement starting with `token`
+    fn parse_statement(&mut self, token: Token) -> Result<Node> {
+        match token.token_type {
+            TokenType::Let => self.parse_let(),
+            TokenType::Struct => self.parse_struct(token),
+            TokenType::Enum => self.parse_enum(token),
+            TokenType::Print => {
+                self.advance();
+                let expr = self.parse_expr(0)?;
+                let mut print_node = Node::new(NodeType::Print, Some(token));
+                print_node.add_child(expr);
+                Ok(print_node)
+            }
+            TokenType::Label(ref name) => {
+                self.advance();
+                self.expect_token(TokenType::Colon)?;
+                match self.peek() {
+                    Some(token)
+                        if matches!(token.token_type, TokenType::While | TokenType::Loop) =>
+                    {
+                        self.parse_loop(token, Some(name.to_string()))
+                    }
+                    Some(token) if token.token_type == TokenType::For => {
+                        self.parse_for(token, Some(name.to_string()))
+                    }
+                    _ => {
+                        let node = Node::new(NodeType::Nil, None);
+                        Err(self.diagnostic(
+                            DiagnosticKind::InvalidBreak,
+                            &format!("The label '{name} must be followed by a loop"),
+                            &node,
+                        ))
                     }
                 }
+            }
+            TokenType::While | TokenType::Loop => self.parse_loop(token, None),
+            TokenType::For => self.parse_for(token, None),
+            TokenType::Break | TokenType::Continue => self.parse_break(token),
+            // A function without a name is an expression
+            TokenType::Func
+                if matches!(
+                    self.lookahead(1).map(|t| &t.token_type),
+                    Some(TokenType::Identifier(_))
+                ) =>
+            {
+                self.parse_function(token)
+            }
+            TokenType::Return => self.parse_return(token),
+            _ => self.parse_expr_statement(),
+        }
+    }
+
+    /// Parse a `while` or `loop` starting with `token`, which may have been given a
+    /// label. A `while` has its condition as the first child.
+    fn parse_loop(&mut self, token: Token, label: Option<String>) -> Result<Node> {
+        self.advance();
+        let boxed = label.clone().map(Box::new);
+        let mut node = if token.token_type == TokenType::While {
+            let mut node = Node::new(NodeType::While(boxed), Some(token));
+            let condition = self.parse_condition()?;
+            self.expect_boolean(&condition, "A `while` condition")?;
+            node.add_child(condition);
+            node
+        } else {
+            Node::new(NodeType::Loop(boxed), Some(token))
+        };
+        self.parse_loop_body(&mut node, label)?;
+        Ok(node)
+    }
+
+    /// Parse `for x in iterable` and the body, where the iterable is a range or an
+    /// array. The variable is only in scope in the body.
+    fn parse_for(&mut self, token: Token, label: Option<String>) -> Result<Node> {
+        self.advance();
+        let mut node = Node::new(NodeType::For(label.clone().map(Box::new)), Some(token));
+        let variable = self.loop_variable()?;
+        // `for key, value in table` is kept for when there are tables to go over
+        if self.match_token(TokenType::Comma) {
+            let value = self.loop_variable()?;
+            return Err(self.diagnostic(
+                DiagnosticKind::InvalidSyntax,
+                "A `for` loop with a key and a value needs a table, and tables aren't supported yet",
+                &value,
+            ));
+        }
+        self.expect_token(TokenType::In)?;
+
+        let iterable = self.parse_condition()?;
+        if !matches!(iterable.return_type, BaseType::Array | BaseType::Undefined) {
+            return Err(self.diagnostic(
+                DiagnosticKind::TypeMismatch,
+                &format!(
+                    "A `for` loop can only go over a range or an array, found {}",
+                    iterable.return_type
+                ),
+                &iterable,
+            ));
+        }
+
+        // Ranges only hold integers
+        self.symbol_table.push_scope();
+        if let NodeType::Ident(name, _) = &variable.node_type {
+            let data_type = match iterable.node_type {
+                NodeType::Range(_) => DataType::Integer,
+                _ => DataType::None,
             };
-            self.advance();
+            self.symbol_table.add_symbol(name, data_type);
+        }
+        node.add_child(variable);
+        node.add_child(iterable);
+        let body = self.parse_loop_body(&mut node, label);
+        self.symbol_table.pop_scope();
+        body?;
+        Ok(node)
+    }
+
+    /// The name of a variable that a `for` loop sets
+    fn loop_variable(&mut self) -> Result<Node> {
+        self.new_identifier()
+            .map_err(|_| self.syntax_error("Expected a loop variable after `for`"))
+    }
+
+    /// Parse the body of a loop and add it to `node`. `break` and `continue` in the
+    /// body refer to this loop unless they name an outer one.
+    fn parse_loop_body(&mut self, node: &mut Node, label: Option<String>) -> Result<()> {
+        // The loop is popped before checking the body so an error doesn't leave it
+        // behind for the next statement
+        self.loops.push(label);
+        let body = self.parse_block();
+        self.loops.pop();
+        node.add_child(body?);
+        Ok(())
+    }
+
+    /// Parse a function such as `func add(a: int, b: int) -> int { a + b }`, or an
+    /// anonymous one such as `func(x) { x * factor }`. Parameters and the return type
+    /// can leave out their types.
+    fn parse_function(&mut self, token: Token) -> Result<Node> {
+        self.advance();
+        let name = match self.peek().map(|t| t.token_type) {
+            Some(TokenType::Identifier(name)) => {
+                self.advance();
+                Some(name.to_string())
+            }
+            _ => None,
+        };
+        let def = FunctionDef {
+            name,
+            captures: Vec::new(),
+        };
+        let mut node = Node::new(NodeType::Function(Box::new(def)), Some(token));
+        node.doc = self.doc.take();
+
+        self.expect_token(TokenType::LParen)?;
+        loop {
+            while self.match_token(TokenType::Newline) {}
+            if self.match_token(TokenType::RParen) {
+                break;
+            }
+            let mut param = self
+                .new_identifier()
+                .map_err(|_| self.syntax_error("Expected a parameter name"))?;
+            if self.match_token(TokenType::Colon) {
+                let annotation = self.parse_type()?;
+                param.return_type = self.types.base_type(&annotation);
+                param.annotation = Some(annotation);
+            }
+            node.add_child(param);
+            while self.match_token(TokenType::Newline) {}
+            if !self.match_token(TokenType::Comma) {
+                self.expect_token(TokenType::RParen)?;
+                break;
+            }
+        }
+        if self.match_token(TokenType::Arrow) {
+            let annotation = self.parse_type()?;
+            node.return_type = self.types.base_type(&annotation);
+            node.annotation = Some(annotation);
+        }
+        // A named function can call itself, so its name is declared before the body
+        if let NodeType::Function(def) = &node.node_type {
+            if let Some(name) = &def.name {
+                let params = node.children.iter().map(|p| p.annotation.clone()).collect();
+                let index = self.types.add_function(FunctionType { params });
+                self.symbol_table
+                    .add_symbol(name, DataType::Function(index));
+            }
+        }
+
+        // The parameters are only in scope in the body, and loops outside the
+        // function can't be broken out of from inside it
+        self.symbol_table.push_function_scope();
+        for param in &node.children {
+            self.declare_variable(param);
+        }
+        let loops = std::mem::take(&mut self.loops);
+        self.returns.push(node.annotation.clone());
+        let body = self.parse_block();
+        self.returns.pop();
+        self.loops = loops;
+        let captures = self.symbol_table.pop_function_scope();
+        if let NodeType::Function(def) = &mut node.node_type {
+            def.captures = captures;
+        }
+        let mut body = body?;
+        if let Some(annotation) = &node.annotation {
+            self.expect_annotation(annotation, &mut body, "The function's result")?;
+        }
+        node.add_child(body);
+        Ok(node)
+    }
+
+    /// Add the variable that `identifier` names to the current scope, with the type
+    /// it was declared with
+    fn declare_variable(&mut self, identifier: &Node) {
+        let NodeType::Ident(name, _) = &identifier.node_type else {
+            return;
+        };
+        match &identifier.annotation {
+            Some(annotation) => {
+                let data_type = self.types.resolve(annotation);
+                let annotation = annotation.clone();
+                self.symbol_table.add_annotated(name, data_type, annotation);
+            }
+            None => self.symbol_table.add_symbol(name, DataType::None),
+        }
+    }
+
+    /// Parse a type annotation such as the `int` in `a: int`. Arrays are written as
+    /// `[int]`, and any other name is a user defined type.
+    fn parse_type(&mut self) -> Result<TypeAnnotation> {
+        match self.peek().map(|t| t.token_type) {
+            Some(TokenType::LBracket) => {
+                self.advance();
+                let element = self.parse_type()?;
+                self.expect_token(TokenType::RBracket)?;
+                return Ok(TypeAnnotation::Array(Box::new(element)));
+            }
+            Some(TokenType::LBrace) => {
+                // There are no tables yet, so there's nothing `{text: int}` could name
+                let token = self.advance();
+                let node = Node::new(NodeType::Nil, token);
+                return Err(self.diagnostic(
+                    DiagnosticKind::UnknownType,
+                    "There are no table types yet",
+                    &node,
+                ));
+            }
+            Some(TokenType::Identifier(name)) => {
+                let token = self.advance();
+                if self.types.find(&name).is_none() {
+                    let node = Node::new(NodeType::Nil, token);
+                    return Err(self.diagnostic(
+                        DiagnosticKind::UnknownType,
+                        &format!("There's no type called {name}"),
+                        &node,
+                    ));
+                }
+                return Ok(TypeAnnotation::Named(name.to_string()));
+            }
+            _ => {}
+        }
+        match self.parse_datatype()? {
+            BaseType::NoType => Err(self.syntax_error("Expected a type")),
+            base_type => {
+                self.advance();
+                Ok(TypeAnnotation::Base(base_type))
+            }
+        }
+    }
+
+    /// Parse a struct declaration such as `struct Point { x: float, y: float }` and add
+    /// it to the type registry. The name is registered before the fields so that they
+    /// can refer to it.
+    fn parse_struct(&mut self, token: Token) -> Result<Node> {
+        self.advance();
+        let Some(TokenType::Identifier(name)) = self.advance().map(|t| t.token_type) else {
+            return Err(self.syntax_error("Expected a struct name"));
+        };
+        let mut node = Node::new(NodeType::Struct(Box::new(name.to_string())), Some(token));
+        node.doc = self.doc.take();
+        let index = self.types.add_struct(StructType {
+            name: name.to_string(),
+            fields: Vec::new(),
+        });
+
+        self.expect_token(TokenType::LBrace)?;
+        loop {
+            while self.match_token(TokenType::Newline) {}
+            if self.match_token(TokenType::RBrace) {
+                break;
+            }
+            let field = self.new_identifier()?;
+            let NodeType::Ident(field_name, _) = &field.node_type else {
+                bail!("Expected a field name");
+            };
+            if self.types.get_struct(index).field(field_name).is_some() {
+                return Err(self.diagnostic(
+                    DiagnosticKind::InvalidField,
+                    &format!("{name} already has a field called {field_name}"),
+                    &field,
+                ));
+            }
+            self.expect_token(TokenType::Colon)?;
+            let annotation = self.parse_type()?;
+            let fields = &mut self.types.get_struct_mut(index).fields;
+            fields.push((field_name.to_string(), annotation));
+            if !self.field_separator() {
+                self.expect_token(TokenType::RBrace)?;
+                break;
+            }
+        }
+        Ok(node)
+    }
+
+    /// Parse a struct literal such as `Point { x: 1, y: 2 }`, which has to set every
+    /// field. The values are put in the order the fields were declared.
+    fn parse_struct_literal(&mut self, token: Token, index: usize) -> Result<Node> {
+        self.advance();
+        self.expect_token(TokenType::LBrace)?;
+        let struct_type = self.types.get_struct(index).clone();
+        let mut values: Vec<Option<Node>> = vec![None; struct_type.fields.len()];
+        loop {
+            while self.match_token(TokenType::Newline) {}
+            if self.match_token(TokenType::RBrace) {
+                break;
+            }
+            let field = self.new_identifier()?;
+            let NodeType::Ident(field_name, _) = &field.node_type else {
+                bail!("Expected a field name");
+            };
+            let Some((position, annotation)) = struct_type.field(field_name) else {
+                return Err(self.diagnostic(
+                    DiagnosticKind::InvalidField,
+                    &format!("{} has no field called {field_name}", struct_type.name),
+                    &field,
+                ));
+            };
+            if values[position].is_some() {
+                return Err(self.diagnostic(
+                    DiagnosticKind::InvalidField,
+                    &format!("{field_name} is set more than once"),
+                    &field,
+                ));
+            }
+            self.expect_token(TokenType::Colon)?;
+            let mut value = self.parse_enclosed_expr()?;
+            self.expect_annotation(annotation, &mut value, &format!("The field {field_name}"))?;
+            values[position] = Some(value);
+            if !self.field_separator() {
+                self.expect_token(TokenType::RBrace)?;
+                break;
+            }
+        }
+
+        let literal = StructLiteral {
+            name: struct_type.name.clone(),
+            fields: struct_type
+                .fields
+                .iter()
+                .map(|(name, annotation)| (name.clone(), self.types.base_type(annotation)))
+                .collect(),
+        };
+        let mut node = Node::typed(
+            NodeType::StructLiteral(Box::new(literal)),
+            Some(token),
+            BaseType::Struct,
+        );
+        for (value, (field_name, _)) in values.into_iter().zip(&struct_type.fields) {
+            let Some(value) = value else {
+                return Err(self.diagnostic(
+                    DiagnosticKind::InvalidField,
+                    &format!("{} needs a value for {field_name}", struct_type.name),
+                    &node,
+                ));
+            };
+            node.add_child(value);
+        }
+        Ok(node)
+    }
+
+    /// Parse an enum declaration such as `enum Shape { Circle(float), Empty }` and add
+    /// it to the type registry. A variant lists the types of the values it carries
+    /// in brackets, if it carries any.
+    fn parse_enum(&mut self, token: Token) -> Result<Node> {
+        self.advance();
+        let Some(TokenType::Identifier(name)) = self.advance().map(|t| t.token_type) else {
+            return Err(self.syntax_error("Expected an enum name"));
+        };
+        let mut node = Node::new(NodeType::Enum(Box::new(name.to_string())), Some(token));
+        node.doc = self.doc.take();
+        let index = self.types.add_enum(EnumType {
+            name: name.to_string(),
+            variants: Vec::new(),
+        });
+
+        self.expect_token(TokenType::LBrace)?;
+        loop {
+            while self.match_token(TokenType::Newline) {}
+            if self.match_token(TokenType::RBrace) {
+                break;
+            }
+            let variant = self.new_identifier()?;
+            let NodeType::Ident(variant_name, _) = &variant.node_type else {
+                bail!("Expected a variant name");
+            };
+            if self.types.get_enum(index).variant(variant_name).is_some() {
+                return Err(self.diagnostic(
+                    DiagnosticKind::InvalidVariant,
+                    &format!("{name} already has a variant called {variant_name}"),
+                    &variant,
+                ));
+            }
+            let mut payload = Vec::new();
+            if self.match_token(TokenType::LParen) {
+                loop {
+                    if self.match_token(TokenType::RParen) {
+                        break;
+                    }
+                    payload.push(self.parse_type()?);
+                    if !self.match_token(TokenType::Comma) {
+                        self.expect_token(TokenType::RParen)?;
+                        break;
+                    }
+                }
+            }
+            let variants = &mut self.types.get_enum_mut(index).variants;
+            variants.push((variant_name.to_string(), payload));
+            if !self.field_separator() {
+                self.expect_token(TokenType::RBrace)?;
+                break;
+            }
+        }
+        Ok(node)
+    }
+
+    /// Parse a value of an enum such as `Shape.Rect(1, 2)`, which needs a value for
+    /// each type the variant carries, or `Shape.Empty`
+    fn parse_variant(&mut self, token: Token, index: usize) -> Result<Node> {
+        self.advance();
+        self.expect_token(TokenType::Dot)?;
+        let enum_type = self.types.get_enum(index).clone();
+        let variant = self.new_identifier()?;
+        let NodeType::Ident(variant_name, _) = &variant.node_type else {
+            bail!("Expected a variant name");
+        };
+        let Some((tag, payload)) = enum_type.variant(variant_name) else {
+            return Err(self.diagnostic(
+                DiagnosticKind::InvalidVariant,
+                &format!("{} has no variant called {variant_name}", enum_type.name),
+                &variant,
+            ));
+        };
+
+        let mut values = Vec::new();
+        if !payload.is_empty() {
+            self.expect_token(TokenType::LParen)?;
+            loop {
+                while self.match_token(TokenType::Newline) {}
+                if self.match_token(TokenType::RParen) {
+                    break;
+                }
+                values.push(self.parse_enclosed_expr()?);
+                while self.match_token(TokenType::Newline) {}
+                if !self.match_token(TokenType::Comma) {
+                    self.expect_token(TokenType::RParen)?;
+                    break;
+                }
+            }
+        }
+        if values.len() != payload.len() {
+            return Err(self.diagnostic(
+                DiagnosticKind::InvalidVariant,
+                &format!(
+                    "{variant_name} carries {} values, found {}",
+                    payload.len(),
+                    values.len()
+                ),
+                &variant,
+            ));
+        }
+
+        for (value, expected) in values.iter_mut().zip(payload) {
+            self.expect_annotation(expected, value, &format!("A value of {variant_name}"))?;
+        }
+        let payload: Vec<BaseType> = payload.iter().map(|a| self.types.base_type(a)).collect();
+        let def = Variant {
+            enum_name: enum_type.name.clone(),
+            name: variant_name.to_string(),
+            tag,
+            payload,
+        };
+        let mut node = Node::typed(
+            NodeType::Variant(Box::new(def)),
+            Some(token),
+            BaseType::Enum,
+        );
+        for value in values {
+            node.add_child(value);
+        }
+        Ok(node)
+    }
+
+    /// Skip the comma or newlines after a field. If neither is there, the closing
+    /// brace has to come next.
+    fn field_separator(&mut self) -> bool {
+        let mut separated = false;
+        while self.match_token(TokenType::Newline) {
+            separated = true;
+        }
+        self.match_token(TokenType::Comma) || separated
+    }
+
+    /// The type of the values that `node` produces, as far as variables and struct
+    /// fields declare it
+    fn data_type_of(&mut self, node: &Node) -> DataType {
+        match &node.node_type {
+            NodeType::Ident(name, _) => self
+                .symbol_table
+                .get(name)
+                .map_or(DataType::None, |item| item.data_type),
+            NodeType::StructLiteral(literal) => {
+                self.types.find(&literal.name).unwrap_or(DataType::None)
+            }
+            NodeType::Variant(variant) => self
+                .types
+                .find(&variant.enum_name)
+                .unwrap_or(DataType::None),
+            NodeType::FieldAccess(name) => match self.data_type_of(&node.children[0]) {
+                DataType::Struct(index) => match self.types.get_struct(index).field(name) {
+                    Some((_, annotation)) => self.types.resolve(annotation),
+                    None => DataType::None,
+                },
+                _ => DataType::None,
+            },
+            _ => DataType::None,
+        }
+    }
+
+    /// Parse the condition of an `if` or `while`, or what a `for` loops over. A
+    /// struct name followed by `{` is left for the body to start there.
+    fn parse_condition(&mut self) -> Result<Node> {
+        let restriction = std::mem::replace(&mut self.no_struct_literals, true);
+        let condition = self.parse_expr(0);
+        self.no_struct_literals = restriction;
+        condition
+    }
+
+    /// Parse an expression between brackets or braces, where struct literals are
+    /// allowed even inside a condition
+    fn parse_enclosed_expr(&mut self) -> Result<Node> {
+        let restriction = std::mem::replace(&mut self.no_struct_literals, false);
+        let expr = self.parse_expr(0);
+        self.no_struct_literals = restriction;
+        expr
+    }
+
+    /// Parse a `return`, which has a value unless the statement ends right after it
+    fn parse_return(&mut self, token: Token) -> Result<Node> {
+        self.advance();
+        let mut node = Node::new(NodeType::Return, Some(token));
+        let Some(expected) = self.returns.last().cloned() else {
+            return Err(self.diagnostic(
+                DiagnosticKind::InvalidReturn,
+                "`return` can only be used inside a function",
+                &node,
+            ));
+        };
+        let ends = matches!(
+            self.peek().map(|t| t.token_type),
+            Some(TokenType::Newline | TokenType::SemiColon | TokenType::RBrace | TokenType::EOF)
+                | None
+        );
+        if !ends {
+            let mut value = self.parse_expr(0)?;
+            if let Some(annotation) = &expected {
+                self.expect_annotation(annotation, &mut value, "The returned value")?;
+            }
+            node.add_child(value);
+        }
+        Ok(node)
+    }
+
+    /// Parse a `break` or `continue`, which must be inside a loop and can name the
+    /// loop it applies to
+    fn parse_break(&mut self, token: Token) -> Result<Node> {
+        self.advance();
+        let label = match self.peek().map(|t| t.token_type) {
+            Some(TokenType::Label(name)) => {
+                self.advance();
+                Some(name.to_string())
+            }
+            _ => None,
+        };
+        let boxed = label.clone().map(Box::new);
+        let (word, node_type) = if token.token_type == TokenType::Break {
+            ("break", NodeType::Break(boxed))
+        } else {
+            ("continue", NodeType::Continue(boxed))
+        };
+        let node = Node::new(node_type, Some(token));
+
+        let found = match &label {
+            Some(label) => self.loops.iter().any(|l| l.as_ref() == Some(label)),
+            None => !self.loops.is_empty(),
+        };
+        if !found {
+            let msg = match label {
+                Some(label) => format!("`{word}` refers to '{label}, which isn't a loop around it"),
+                None => format!("`{word}` can only be used inside a loop"),
+            };
+            return Err(self.diagnostic(DiagnosticKind::InvalidBreak, &msg, &node));
+        }
+        Ok(node)
+    }
+
+    /// A statement ends at a newline or `;`, or where the enclosing block does
+    fn end_statement(&mut self, end: &TokenType) -> Result<()> {
+        match self.peek().map(|t| t.token_type) {
+            Some(TokenType::Newline | TokenType::SemiColon) => {
+                self.advance();
+                Ok(())
+            }
+            Some(TokenType::EOF) | None => Ok(()),
+            Some(t) if t == *end => Ok(()),
+            Some(t) => {
+                let msg = format!("Unexpected token {:?} after the end of a statement", t);
+                Err(self.syntax_error(&msg))
+            }
+        }
+    }
+
+    /// Parse a `{ ... }` block of statements. Variables declared in it are only in scope
+    /// until the closing brace.
+    fn parse_block(&mut self) -> Result<Node> {
+        let token = self.expect_token(TokenType::LBrace)?;
+        let mut node = Node::new(NodeType::Block, Some(token));
+        let restriction = std::mem::replace(&mut self.no_struct_literals, false);
+        self.symbol_table.push_scope();
+        let statements = self.parse_statements(&mut node, TokenType::RBrace);
+        self.symbol_table.pop_scope();
+        self.no_struct_literals = restriction;
+        statements?;
+        self.expect_token(TokenType::RBrace)?;
+        node.return_type = match node.children.last() {
+            Some(last) if !last.node_type.is_statement() => last.return_type.clone(),
+            _ => BaseType::NoType,
+        };
+        Ok(node)
+    }
+
+    /// Parse an `if` expression. The `if` has been consumed. Each branch is a block,
+    /// and `else if` chains are nested in the `else` branch.
+    fn parse_if(&mut self, token: Token) -> Result<Node> {
+        let mut node = Node::new(NodeType::If, Some(token));
+        let condition = self.parse_condition()?;
+        self.expect_boolean(&condition, "An `if` condition")?;
+        node.add_child(condition);
+        node.add_child(self.parse_block()?);
+
+        // `else` can start on the line after the closing brace
+        let before_newlines = self.current;
+        while self.match_token(TokenType::Newline) {}
+        if self.match_token(TokenType::Else) {
+            let branch = match self.peek() {
+                Some(token) if token.token_type == TokenType::If => {
+                    self.advance();
+                    self.parse_if(token)?
+                }
+                _ => self.parse_block()?,
+            };
+            // The value only has a known type if both branches agree on it
+            if node.children[1].return_type == branch.return_type {
+                node.return_type = branch.return_type.clone();
+            }
+            node.add_child(branch);
+        } else {
+            self.current = before_newlines;
+        }
+        Ok(node)
+    }
+
+    /// Report a type error if `node` is known not to produce a boolean
+    fn expect_boolean(&mut self, node: &Node, what: &str) -> Result<()> {
+        match node.return_type {
+            BaseType::Boolean | BaseType::Undefined => Ok(()),
+            ref found => Err(self.diagnostic(
+                DiagnosticKind::TypeMismatch,
+                &format!("{what} must be a boolean, found {found}"),
+                node,
+            )),
+        }
+    }
+
+    /// Report a type error if `node` is known to produce a value that doesn't fit the
+    /// `expected` type. Integers fit where floats are expected.
+    /// Check that the value of `node` fits a declared type. The elements of an array
+    /// literal are checked too, and the literal keeps the type so the elements can be
+    /// made to fit it.
+    fn expect_annotation(
+        &mut self,
+        expected: &TypeAnnotation,
+        node: &mut Node,
+        what: &str,
+    ) -> Result<()> {
+        let TypeAnnotation::Array(element) = expected else {
+            let data_type = self.types.resolve(expected);
+            self.expect_type(&data_type.base_type(), node, what)?;
+            // Structs and enums are only the same type if they're the same declaration
+            let found = self.data_type_of(node);
+            if let (DataType::Struct(_) | DataType::Enum(_), Some(name)) =
+                (data_type, self.types.name_of(found))
+            {
+                if found != data_type {
+                    let msg = format!("{what} must be a {expected}, found {name}");
+                    return Err(self.diagnostic(DiagnosticKind::TypeMismatch, &msg, node));
+                }
+            }
+            return Ok(());
+        };
+        self.expect_type(&BaseType::Array, node, what)?;
+        if node.node_type == NodeType::Block {
+            // A block's value is its last statement's
+            if let Some(last) = node.children.last_mut() {
+                return self.expect_annotation(expected, last, what);
+            }
+        } else if let NodeType::Array(_) = node.node_type {
+            for child in &mut node.children {
+                self.expect_annotation(element, child, "An element")?;
+            }
+            node.annotation = Some(expected.clone());
+        } else if let Some(found @ TypeAnnotation::Array(_)) = &node.annotation {
+            if found != expected {
+                let msg = format!("{what} must be a {expected}, found {found}");
+                return Err(self.diagnostic(DiagnosticKind::TypeMismatch, &msg, node));
+            }
+        }
+        Ok(())
+    }
+
+    fn expect_type(&mut self, expected: &BaseType, node: &Node, what: &str) -> Result<()> {
+        let fits = match (expected, &node.return_type) {
+            (BaseType::Undefined | BaseType::NoType, _) => true,
+            (_, BaseType::Undefined | BaseType::NoType) => true,
+            (BaseType::Float, BaseType::Integer) => true,
+            (expected, found) => expected == found,
+        };
+        if fits {
+            return Ok(());
+        }
+        Err(self.diagnostic(
+            DiagnosticKind::TypeMismatch,
+            &format!("{what} must be a {expected}, found {}", node.return_type),
+            node,
+        ))
+    }
+
+    /// Parse an expression used as a statement. An expression followed by `=` or a
+    /// compound operator such as `+=` is the target of an assignment, and must be a
+    /// variable, an array element or a field.
+    fn parse_expr_statement(&mut self) -> Result<Node> {
+        let target = self.parse_expr(0)?;
+        let Some(token) = self.peek() else {
+            return Ok(target);
+        };
+        let node_type = match token.token_type {
+            Assign => NodeType::Assignment,
+            PlusAssign => NodeType::CompoundAssignment(BinOp::Add),
+            MinusAssign => NodeType::CompoundAssignment(BinOp::Sub),
+            StarAssign => NodeType::CompoundAssignment(BinOp::Mul),
+            SlashAssign => NodeType::CompoundAssignment(BinOp::Div),
+            PercentAssign => NodeType::CompoundAssignment(BinOp::Mod),
+            _ => return Ok(target),
+        };
+        if !matches!(
+            target.node_type,
+            NodeType::Ident(..) | NodeType::ElementIndex | NodeType::FieldAccess(_)
+        ) {
+            return Err(self.diagnostic(
+                DiagnosticKind::InvalidAssignment,
+                "Only variables, array elements and fields can be assigned to",
+                &target,
+            ));
+        }
+        // The name of a function declared with `func` always refers to it, so that
+        // calls to it can be checked
+        if let DataType::Function(_) = self.data_type_of(&target) {
+            return Err(self.diagnostic(
+                DiagnosticKind::InvalidAssignment,
+                "A function declared with `func` can't be assigned to",
+                &target,
+            ));
+        }
+        self.advance();
+
+        let mut value = self.parse_expr(0)?;
+        let expected = target.return_type.clone();
+        match node_type {
+            // What a compound assignment stores is the result of its operator
+            NodeType::CompoundAssignment(op) => {
+                let return_type = binary_type(op, &target.return_type, &value.return_type);
+                let result = Node::typed(BinaryOp(op), Some(token.clone()), return_type);
+                self.expect_type(&expected, &result, "The result")?;
+            }
+            _ => match &target.annotation {
+                Some(annotation) => {
+                    let annotation = annotation.clone();
+                    self.expect_annotation(&annotation, &mut value, "The assigned value")?
+                }
+                None => self.expect_type(&expected, &value, "The assigned value")?,
+            },
         }
+        let mut node = Node::new(node_type, Some(token));
+        node.add_child(target);
+        node.add_child(value);
         Ok(node)
     }
 
@@ -190,8 +1120,7 @@ impl Parser {
             }
         }
         let msg = format!("Expected token {:?} not found", token_type);
-        self.raise_error(&msg);
-        Err(Error::msg(msg))
+        Err(self.syntax_error(&msg))
     }
 
     /// Digs down to the base unit: a number, an identifier, or a parenthesized sub-expression
@@ -204,58 +1133,332 @@ impl Parser {
             // Value operands
             TokenType::Integer(value) => {
                 self.advance();
-                Ok(Node::new(NodeType::Integer(value), Some(token.clone())))
+                Ok(Node::typed(
+                    NodeType::Integer(value),
+                    Some(token.clone()),
+                    BaseType::Integer,
+                ))
             }
             TokenType::Boolean(value) => {
                 self.advance();
-                Ok(Node::new(NodeType::Boolean(value), Some(token.clone())))
+                Ok(Node::typed(
+                    NodeType::Boolean(value),
+                    Some(token.clone()),
+                    BaseType::Boolean,
+                ))
+            }
+            TokenType::Char(value) => {
+                self.advance();
+                Ok(Node::typed(
+                    NodeType::Char(value),
+                    Some(token.clone()),
+                    BaseType::Char,
+                ))
+            }
+            TokenType::Nil => {
+                self.advance();
+                Ok(Node::new(NodeType::Nil, Some(token.clone())))
             }
             TokenType::Text(value) => {
                 self.advance();
-                Ok(Node::new(
+                Ok(Node::typed(
                     NodeType::Text(Box::new(value)),
                     Some(token.clone()),
+                    BaseType::Text,
                 ))
             }
             TokenType::Float(value) => {
                 self.advance();
-                Ok(Node::new(NodeType::Float(value), Some(token.clone())))
+                Ok(Node::typed(
+                    NodeType::Float(value),
+                    Some(token.clone()),
+                    BaseType::Float,
+                ))
+            }
+            TokenType::If => {
+                self.advance();
+                self.parse_if(token)
             }
             TokenType::Identifier(name) => {
+                let next = self.lookahead(1).map(|t| t.token_type.clone());
+                if next == Some(TokenType::LBrace) && !self.no_struct_literals {
+                    if let Some(index) = self.types.find_struct(&name) {
+                        return self.parse_struct_literal(token, index);
+                    }
+                }
+                if next == Some(TokenType::Dot) {
+                    if let Some(index) = self.types.find_enum(&name) {
+                        return self.parse_variant(token, index);
+                    }
+                }
+                // Note the variables that functions use from the ones around them, and
+                // give the use the type the variable was declared with
+                let item = self.symbol_table.capture(&name);
+                let mut node = self.new_identifier()?;
+                match item {
+                    Some(item) => {
+                        node.return_type = item.data_type.base_type();
+                        node.annotation = item.annotation;
+                    }
+                    None => {
+                        let top_level = self.returns.is_empty();
+                        self.unresolved.push((node.clone(), top_level));
+                    }
+                }
+                Ok(node)
+            }
+            TokenType::Func => self.parse_function(token),
+            TokenType::Interpolated(parts) => {
                 self.advance();
-                self.new_identifier()
+                self.parse_interpolation(token, parts)
             }
             TokenType::LParen => {
                 self.advance();
-                let expr = self.parse_expr(0)?;
+                let expr = self.parse_enclosed_expr()?;
                 self.expect_token(TokenType::RParen)?;
                 Ok(expr)
             }
+            TokenType::LBracket => {
+                self.advance();
+                self.parse_array(token)
+            }
+            TokenType::LBrace => self.parse_block(),
             // Unary operators
             TokenType::Plus => {
                 self.advance();
                 // A plus has no effect as a unary operator, so just try and get the next one
-                self.parse_primary()
+                self.parse_postfix()
             }
             TokenType::Minus => self.parse_unary(token, UnOp::Neg),
-            TokenType::Bang => self.parse_unary(token, UnOp::Not),
-            _ => Err(anyhow!(format!("Unexpected token {:?}", token.token_type))),
+            TokenType::Bang | TokenType::Not => self.parse_unary(token, UnOp::Not),
+            TokenType::Tilde => self.parse_unary(token, UnOp::BitNot),
+            _ => {
+                let msg = format!("Unexpected token {:?}", token.token_type);
+                Err(self.syntax_error(&msg))
+            }
+        }
+    }
+
+    /// Parse the elements of an array literal. The `[` has been consumed. Elements
+    /// can be spread over several lines and a trailing comma is allowed.
+    fn parse_array(&mut self, token: Token) -> Result<Node> {
+        let mut node = Node::new(NodeType::Array(Box::new(NodeType::Undefined)), Some(token));
+        loop {
+            while self.match_token(TokenType::Newline) {}
+            if self.match_token(TokenType::RBracket) {
+                break;
+            }
+            let element = self.parse_enclosed_expr()?;
+            // The elements share one type, so the first that's known sets it
+            let known = node
+                .children
+                .iter()
+                .map(|e| &e.return_type)
+                .find(|t| !matches!(t, BaseType::Undefined | BaseType::NoType));
+            if let Some(first) = known {
+                if !matches!(element.return_type, BaseType::Undefined | BaseType::NoType)
+                    && element.return_type != *first
+                {
+                    let msg = format!(
+                        "Array elements must all have the same type, found {first} and {}",
+                        element.return_type
+                    );
+                    return Err(self.diagnostic(DiagnosticKind::TypeMismatch, &msg, &element));
+                }
+            }
+            node.add_child(element);
+            while self.match_token(TokenType::Newline) {}
+            if !self.match_token(TokenType::Comma) {
+                self.expect_token(TokenType::RBracket)?;
+                break;
+            }
         }
+        node.return_type = BaseType::Array;
+        Ok(node)
+    }
+
+    /// Parse a primary expression followed by any `[index]`, `.field` or `(arguments)`
+    /// suffixes
+    fn parse_postfix(&mut self) -> Result<Node> {
+        let mut node = self.parse_primary()?;
+        while let Some(token) = self.peek() {
+            let suffix = match token.token_type {
+                TokenType::LBracket => {
+                    self.advance();
+                    let index = self.parse_enclosed_expr()?;
+                    self.expect_token(TokenType::RBracket)?;
+
+                    let mut index_node = Node::new(NodeType::ElementIndex, Some(token));
+                    // The elements of an array whose type was declared have that type
+                    if let Some(TypeAnnotation::Array(element)) = &node.annotation {
+                        index_node.return_type = self.types.base_type(element);
+                        index_node.annotation = Some(*element.clone());
+                    }
+                    index_node.add_child(node);
+                    index_node.add_child(index);
+                    index_node
+                }
+                TokenType::Dot => {
+                    self.advance();
+                    let field = self.advance();
+                    let Some(TokenType::Identifier(name)) = field.map(|t| t.token_type) else {
+                        return Err(self.syntax_error("Expected a field name after `.`"));
+                    };
+                    let mut field_node = Node::new(
+                        NodeType::FieldAccess(Box::new(name.to_string())),
+                        Some(token),
+                    );
+                    // Fields of a value whose struct is known are checked here
+                    if let DataType::Struct(index) = self.data_type_of(&node) {
+                        let struct_type = self.types.get_struct(index);
+                        match struct_type.field(&name) {
+                            Some((_, annotation)) => {
+                                field_node.return_type = self.types.base_type(annotation);
+                                field_node.annotation = Some(annotation.clone());
+                            }
+                            None => {
+                                let msg =
+                                    format!("{} has no field called {name}", struct_type.name);
+                                return Err(self.diagnostic(
+                                    DiagnosticKind::InvalidField,
+                                    &msg,
+                                    &field_node,
+                                ));
+                            }
+                        }
+                    }
+                    field_node.add_child(node);
+                    field_node
+                }
+                TokenType::LParen => {
+                    self.advance();
+                    let params = match self.data_type_of(&node) {
+                        DataType::Function(index) => Some(self.types.get_function(index).clone()),
+                        _ => None,
+                    };
+                    let mut call = Node::new(NodeType::Call, Some(token));
+                    call.add_child(node);
+                    loop {
+                        while self.match_token(TokenType::Newline) {}
+                        if self.match_token(TokenType::RParen) {
+                            break;
+                        }
+                        call.add_child(self.parse_enclosed_expr()?);
+                        while self.match_token(TokenType::Newline) {}
+                        if !self.match_token(TokenType::Comma) {
+                            self.expect_token(TokenType::RParen)?;
+                            break;
+                        }
+                    }
+                    // Calls to functions declared with `func` are checked here, and
+                    // any other call when it runs
+                    if let Some(FunctionType { params }) = params {
+                        let args = &mut call.children[1..];
+                        if params.len() != args.len() {
+                            let msg = format!(
+                                "The function takes {} arguments but was given {}",
+                                params.len(),
+                                args.len()
+                            );
+                            return Err(self.diagnostic(DiagnosticKind::InvalidCall, &msg, &call));
+                        }
+                        for (param, arg) in params.iter().zip(args) {
+                            if let Some(param) = param {
+                                self.expect_annotation(param, arg, "The argument")?;
+                            }
+                        }
+                    }
+                    call
+                }
+                _ => break,
+            };
+            node = suffix;
+        }
+        Ok(node)
+    }
+
+    /// Parse an interpolated string into a node whose children are the text pieces
+    /// and embedded expressions, in order
+    fn parse_interpolation(&mut self, token: Token, parts: Vec<StringPart>) -> Result<Node> {
+        let mut node = Node::new(NodeType::Interpolation, Some(token.clone()));
+        for part in parts {
+            match part {
+                StringPart::Text(text) => {
+                    let text_node = Node::new(NodeType::Text(Box::new(text)), Some(token.clone()));
+                    node.add_child(text_node);
+                }
+                StringPart::Expr(mut tokens) => {
+                    let last = tokens.last().unwrap_or(&token);
+                    let (location, span) = (last.location, last.span);
+                    tokens.push(Token {
+                        token_type: TokenType::EOF,
+                        location,
+                        span: Span::new(span.file_id, span.end_byte, span.end_byte),
+                        trivia: None,
+                    });
+
+                    // The embedded tokens are parsed in place so they share the parser's state
+                    let outer_tokens = std::mem::replace(&mut self.tokens, tokens);
+                    let outer_stream = self.stream.take();
+                    let outer_current = std::mem::replace(&mut self.current, 0);
+                    let expr = self.parse_embedded_expr();
+                    self.tokens = outer_tokens;
+                    self.stream = outer_stream;
+                    self.current = outer_current;
+
+                    node.add_child(expr?);
+                }
+            }
+        }
+        node.return_type = BaseType::Text;
+        Ok(node)
+    }
+
+    /// Parse the tokens of a `${...}` expression, which must hold exactly one expression
+    fn parse_embedded_expr(&mut self) -> Result<Node> {
+        while self.match_token(TokenType::Newline) {}
+        let expr = self.parse_expr(0)?;
+        while self.match_token(TokenType::Newline) {}
+        self.expect_token(TokenType::EOF)?;
+        Ok(expr)
     }
 
     fn parse_unary(&mut self, token: Token, unop: UnOp) -> Result<Node> {
         self.advance();
         // After the unary, we recursively call the function to get at the
         // value being negated
-        let u_node = self.parse_primary()?;
+        let u_node = self.parse_postfix()?;
         let mut node = Node::new(NodeType::UnaryOp(unop), Some(token));
+        node.return_type = match unop {
+            UnOp::Neg => u_node.return_type.clone(),
+            UnOp::Not => BaseType::Boolean,
+            UnOp::BitNot => BaseType::Integer,
+        };
         node.add_child(u_node);
         Ok(node)
     }
 
+    /// Parse a binary expression by precedence climbing. From loosest to tightest:
+    ///
+    /// | Precedence | Operators                  | Associativity |
+    /// |------------|----------------------------|---------------|
+    /// | 1          | `or` `\|\|`                  | left          |
+    /// | 2          | `and` `&&`                 | left          |
+    /// | 3          | `==` `!=`                  | left          |
+    /// | 4          | `<` `<=` `>` `>=`          | left          |
+    /// | 5          | `\|`                       | left          |
+    /// | 6          | `^`                        | left          |
+    /// | 7          | `&`                        | left          |
+    /// | 8          | `<<` `>>`                  | left          |
+    /// | 10         | `+` `-`                    | left          |
+    /// | 20         | `*` `/` `~/` `%`           | left          |
+    /// | 30         | `**`                       | right         |
+    ///
+    /// Unary operators (`-`, `!`, `not`, `~`) bind tighter than any of these, and a
+    /// range (`a..b` or `a..=b`) is looser than all of them.
     fn parse_expr(&mut self, min_prec: u8) -> Result<Node> {
         // First, parse a primary expression (a number or parenthesized expr)
-        let mut node = self.parse_primary()?;
+        let mut node = self.parse_postfix()?;
 
         // Now, try to consume operators that have at least 'min_prec'
         loop {
@@ -264,11 +1467,26 @@ impl Parser {
             let token_type = token.clone().token_type;
 
             let (prec, op) = match token_type {
+                Or | OrOr => (1, BinOp::Or),
+                And | AndAnd => (2, BinOp::And),
+                Equal => (3, BinOp::Eq),
+                NotEqual => (3, BinOp::Ne),
+                LessThan => (4, BinOp::Lt),
+                LessEqual => (4, BinOp::Le),
+                GreaterThan => (4, BinOp::Gt),
+                GreaterEqual => (4, BinOp::Ge),
+                Pipe => (5, BinOp::BitOr),
+                Caret => (6, BinOp::BitXor),
+                Ampersand => (7, BinOp::BitAnd),
+                ShiftLeft => (8, BinOp::Shl),
+                ShiftRight => (8, BinOp::Shr),
                 Plus => (10, BinOp::Add),
                 Minus => (10, BinOp::Sub),
                 Star => (20, BinOp::Mul),
                 Slash => (20, BinOp::Div),
-                Caret => {
+                TildeSlash => (20, BinOp::IntDiv),
+                Percent => (20, BinOp::Mod),
+                StarStar => {
                     is_right_associative = true;
                     (30, BinOp::Pow)
                 }
@@ -291,13 +1509,599 @@ impl Parser {
             let lhs = node.clone();
 
             node = Node::new(BinaryOp(op), Some(token));
+            node.return_type = binary_type(op, &lhs.return_type, &rhs.return_type);
             node.add_child(rhs);
             node.add_child(lhs);
         }
 
+        // A range is looser than every operator and can't be chained, so it only
+        // comes at the top of an expression
+        if min_prec == 0 {
+            if let Some(token) = self.peek() {
+                if matches!(token.token_type, DotDot | DotDotEqual) {
+                    self.advance();
+                    let inclusive = token.token_type == DotDotEqual;
+                    let end = self.parse_expr(1)?;
+                    for bound in [&node, &end] {
+                        if !matches!(bound.return_type, BaseType::Integer | BaseType::Undefined) {
+                            return Err(self.diagnostic(
+                                DiagnosticKind::TypeMismatch,
+                                &format!(
+                                    "The ends of a range must be integers, found {}",
+                                    bound.return_type
+                                ),
+                                bound,
+                            ));
+                        }
+                    }
+                    let start = node;
+                    node = Node::typed(Range(inclusive), Some(token), BaseType::Array);
+                    node.add_child(end);
+                    node.add_child(start);
+                }
+            }
+        }
+
         Ok(node)
     }
 }
+/// The type of a binary operation's result, if it's known from its operands
+fn binary_type(op: BinOp, lhs: &BaseType, rhs: &BaseType) -> BaseType {
+    use BaseType::{Float as F, Integer as I};
+    match op {
+        BinOp::Eq
+        | BinOp::Ne
+        | BinOp::Lt
+        | BinOp::Le
+        | BinOp::Gt
+        | BinOp::Ge
+        | BinOp::And
+        | BinOp::Or => BaseType::Boolean,
+        BinOp::BitAnd | BinOp::BitOr | BinOp::BitXor | BinOp::Shl | BinOp::Shr => I,
+        // A negative exponent makes a float even from integers
+        BinOp::Pow => match (lhs, rhs) {
+            (I | F, F) | (F, I) => F,
+            _ => BaseType::Undefined,
+        },
+        BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::IntDiv | BinOp::Mod => {
+            match (lhs, rhs) {
+                (I, I) => I,
+                (I | F, I | F) => F,
+                _ => BaseType::Undefined,
+            }
+        }
+    }
+}
+
 pub fn parse(tokens: Vec<Token>, source_code: String) -> Result<Node> {
     Parser::new(tokens, source_code).parse()
 }
+
+/// Parse tokens as they're lexed, rather than lexing all of the code first
+pub fn parse_stream(tokens: Tokens, source_code: String) -> Result<Node> {
+    Parser::from_stream(tokens, source_code).parse()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lexer::{lex, lex_stream, LexMode, SourceType};
+
+    /// Parse code that can use the variables `a` to `z` without declaring them
+    fn parse_code(code: &str) -> Node {
+        let tokens = lex(code, SourceType::Test).unwrap();
+        let mut parser = Parser::new(tokens, code.to_string());
+        for name in 'a'..='z' {
+            parser
+                .symbol_table
+                .add_symbol(&name.to_string(), DataType::None);
+        }
+        parser.parse().unwrap()
+    }
+
+    /// Parse code that has a problem and return the kind of the first one found
+    fn parse_error(code: &str) -> DiagnosticKind {
+        let tokens = lex(code, SourceType::Test).unwrap();
+        let err = parse(tokens, code.to_string()).unwrap_err();
+        let diagnostics = err.downcast_ref::<Diagnostics>().unwrap();
+        diagnostics.0[0].kind
+    }
+
+    #[test]
+    fn test_streamed_tokens() {
+        // Tokens are only lexed once the parser gets to them
+        let code = "let x = 1\nprint x + 2";
+        let tokens = lex_stream(code, SourceType::Test, 0, LexMode::Normal);
+        let mut parser = Parser::from_stream(tokens, code.to_string());
+        parser.peek();
+        assert_eq!(parser.tokens.len(), 1);
+        let streamed = parser.parse().unwrap();
+        let collected = parse(lex(code, SourceType::Test).unwrap(), code.to_string()).unwrap();
+        assert_eq!(format!("{streamed:?}"), format!("{collected:?}"));
+
+        // Problems the lexer finds are reported ahead of the parser's, even after
+        // where the parser stopped
+        let code = "let = 1\nlet s = \"abc";
+        let tokens = lex_stream(code, SourceType::Test, 0, LexMode::Normal);
+        let err = parse_stream(tokens, code.to_string()).unwrap_err();
+        let Diagnostics(diagnostics) = err.downcast_ref::<Diagnostics>().unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].kind, DiagnosticKind::UnterminatedString);
+    }
+
+    #[test]
+    fn test_doc_comments() {
+        let code = "/// The answer\n/// to everything\nlet a = 42\nlet b = 1\n/** Just b */\nprint 1\nlet c = 2\n/// Says hi\nfunc hi() { print 1 }";
+        let root = parse_code(code);
+        let docs: Vec<_> = root.children.iter().map(|n| n.doc.as_deref()).collect();
+        assert_eq!(
+            docs,
+            vec![
+                Some("The answer\nto everything"),
+                None,
+                None,
+                None,
+                Some("Says hi")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_index_expressions() {
+        let root = parse_code("a[1][2] = -b[0]");
+        let assignment = &root.children[0];
+        assert_eq!(assignment.node_type, NodeType::Assignment);
+
+        let target = &assignment.children[0];
+        assert_eq!(target.node_type, NodeType::ElementIndex);
+        assert_eq!(target.children[0].node_type, NodeType::ElementIndex);
+        assert_eq!(target.children[1].node_type, NodeType::Integer(2));
+
+        // Indexing binds tighter than negation
+        let value = &assignment.children[1];
+        assert_eq!(value.node_type, NodeType::UnaryOp(UnOp::Neg));
+        assert_eq!(value.children[0].node_type, NodeType::ElementIndex);
+    }
+
+    #[test]
+    fn test_array_literals() {
+        for code in ["[1, 2.5]", "[1, \"a\"]", "[[1], 2]", "[true, 1]"] {
+            assert_eq!(parse_error(code), DiagnosticKind::TypeMismatch, "{code}");
+        }
+        parse_code("[1, 2]\n[[1], [2, 3]]");
+    }
+
+    #[test]
+    fn test_unknown_variables() {
+        for code in [
+            "print t",
+            "{ let t = 1 }\nprint t",
+            "for i in 0..2 { }\nprint i",
+            "func f(a) { a }\nprint a",
+            "let x = x",
+            "print f(1)\n{ func f(a) { a } }",
+            "print y\nlet y = 1",
+            "func f() { g }\n{ let g = 1 }",
+        ] {
+            assert_eq!(parse_error(code), DiagnosticKind::UnknownVariable, "{code}");
+        }
+    }
+
+    #[test]
+    fn test_comparison_precedence() {
+        // Comparisons bind more loosely than arithmetic
+        let root = parse_code("1 + 2 < 3 * 4 == true");
+        let eq = &root.children[0];
+        assert_eq!(eq.node_type, NodeType::BinaryOp(BinOp::Eq));
+        assert_eq!(eq.return_type, BaseType::Boolean);
+        let lt = &eq.children[1];
+        assert_eq!(lt.node_type, NodeType::BinaryOp(BinOp::Lt));
+        assert_eq!(lt.children[0].node_type, NodeType::BinaryOp(BinOp::Mul));
+        assert_eq!(lt.children[1].node_type, NodeType::BinaryOp(BinOp::Add));
+    }
+
+    #[test]
+    fn test_logical_precedence() {
+        // `or` binds more loosely than `and`, which binds more loosely than comparisons
+        let root = parse_code("a or b && c == d");
+        let or = &root.children[0];
+        assert_eq!(or.node_type, NodeType::BinaryOp(BinOp::Or));
+        let and = &or.children[0];
+        assert_eq!(and.node_type, NodeType::BinaryOp(BinOp::And));
+        assert_eq!(and.children[0].node_type, NodeType::BinaryOp(BinOp::Eq));
+        assert_eq!(
+            parse_code("!a").children[0].node_type,
+            NodeType::UnaryOp(UnOp::Not)
+        );
+    }
+
+    #[test]
+    fn test_operator_precedence() {
+        // Render the tree with explicit parentheses, left operand first
+        fn group(node: &Node) -> String {
+            match &node.node_type {
+                NodeType::BinaryOp(op) => format!(
+                    "({} {op} {})",
+                    group(&node.children[1]),
+                    group(&node.children[0])
+                ),
+                NodeType::UnaryOp(op) => format!("({op} {})", group(&node.children[0])),
+                NodeType::Ident(name, _) => name.to_string(),
+                other => other.to_string(),
+            }
+        }
+        let grouped = |code: &str| group(&parse_code(code).children[0]);
+
+        assert_eq!(grouped("a | b ^ c & d"), "(a bor (b bxor (c band d)))");
+        assert_eq!(grouped("a & b << c + d"), "(a band (b shl (c add d)))");
+        assert_eq!(grouped("a == b | c"), "(a eq (b bor c))");
+        assert_eq!(grouped("a < b ^ c"), "(a lt (b bxor c))");
+        assert_eq!(grouped("a + b % c ~/ d"), "(a add ((b mod c) idiv d))");
+        assert_eq!(grouped("a ** b ** c * d"), "((a pow (b pow c)) mul d)");
+        assert_eq!(grouped("a >> b >> c"), "((a shr b) shr c)");
+        assert_eq!(grouped("~a & -b"), "((bnot a) band (neg b))");
+        assert_eq!(grouped("a or b | c and d"), "(a or ((b bor c) and d))");
+    }
+
+    #[test]
+    fn test_if() {
+        let root = parse_code("if a { 1 } else if b {\n 2\n}\nelse { 3 }\nprint 4");
+        assert_eq!(root.children.len(), 2);
+        let node = &root.children[0];
+        assert_eq!(node.node_type, NodeType::If);
+        assert_eq!(node.children[1].node_type, NodeType::Block);
+        let else_if = &node.children[2];
+        assert_eq!(else_if.node_type, NodeType::If);
+        assert_eq!(else_if.children[2].node_type, NodeType::Block);
+        assert_eq!(node.return_type, BaseType::Integer);
+
+        // Without an `else` the newline still ends the statement
+        let root = parse_code("if a { print 1 }\nprint 2");
+        assert_eq!(root.children.len(), 2);
+        assert_eq!(root.children[0].children.len(), 2);
+
+        for code in [
+            "if 1 { 2 }",
+            "if 1 + 2.5 { 2 }",
+            "let x = if true { 1 } else if \"no\" { 2 }",
+        ] {
+            assert_eq!(parse_error(code), DiagnosticKind::TypeMismatch, "{code}");
+        }
+    }
+
+    #[test]
+    fn test_assignment_targets() {
+        for (code, node_type) in [
+            ("x = 1", NodeType::Assignment),
+            ("x -= 1", NodeType::CompoundAssignment(BinOp::Sub)),
+            ("a[0] *= 2", NodeType::CompoundAssignment(BinOp::Mul)),
+            ("p.x = 2", NodeType::Assignment),
+            ("p.pos.y /= 2", NodeType::CompoundAssignment(BinOp::Div)),
+        ] {
+            assert_eq!(parse_code(code).children[0].node_type, node_type);
+        }
+        let target = &parse_code("p.pos.y = 1").children[0].children[0];
+        assert_eq!(
+            target.node_type,
+            NodeType::FieldAccess(Box::new("y".into()))
+        );
+        assert_eq!(
+            target.children[0].node_type,
+            NodeType::FieldAccess(Box::new("pos".into()))
+        );
+
+        for code in [
+            "1 + 2 = 3",
+            "let x = 1\nx + 1 += 2",
+            "(1) = 2",
+            "\"a\" = \"b\"",
+        ] {
+            assert_eq!(
+                parse_error(code),
+                DiagnosticKind::InvalidAssignment,
+                "{code}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_loops() {
+        let root = parse_code("'outer: while x < 3 {\n loop { break 'outer }\n continue\n}");
+        let node = &root.children[0];
+        assert_eq!(
+            node.node_type,
+            NodeType::While(Some(Box::new("outer".into())))
+        );
+        let body = &node.children[1];
+        assert_eq!(body.children[0].node_type, NodeType::Loop(None));
+        assert_eq!(
+            body.children[0].children[0].children[0].node_type,
+            NodeType::Break(Some(Box::new("outer".into())))
+        );
+        assert_eq!(body.children[1].node_type, NodeType::Continue(None));
+
+        for (code, kind) in [
+            ("break", DiagnosticKind::InvalidBreak),
+            ("loop { }\ncontinue", DiagnosticKind::InvalidBreak),
+            ("'a: loop { break 'b }", DiagnosticKind::InvalidBreak),
+            ("'a: print 1", DiagnosticKind::InvalidBreak),
+            ("while 1 { }", DiagnosticKind::TypeMismatch),
+        ] {
+            assert_eq!(parse_error(code), kind, "{code}");
+        }
+    }
+
+    #[test]
+    fn test_for() {
+        let root = parse_code("for x in a + 1..=n * 2 { print x }");
+        let node = &root.children[0];
+        assert_eq!(node.node_type, NodeType::For(None));
+        assert_eq!(node.children.len(), 3);
+        let range = &node.children[1];
+        assert_eq!(range.node_type, NodeType::Range(true));
+        assert_eq!(range.return_type, BaseType::Array);
+        // The end then the start, as with binary operators
+        assert_eq!(range.children[0].node_type, NodeType::BinaryOp(BinOp::Mul));
+        assert_eq!(range.children[1].node_type, NodeType::BinaryOp(BinOp::Add));
+
+        let root = parse_code("'a: for x in [1, 2] { break 'a }");
+        assert_eq!(
+            root.children[0].node_type,
+            NodeType::For(Some(Box::new("a".into())))
+        );
+
+        // There are no tables yet for a key and a value to come from
+        assert_eq!(
+            parse_error("for k, v in [1] { }"),
+            DiagnosticKind::InvalidSyntax
+        );
+    }
+
+    #[test]
+    fn test_functions() {
+        let root = parse_code("func add(a: int,\n b) -> float { a }\nadd(1, 2)(3)");
+        let node = &root.children[0];
+        let NodeType::Function(def) = &node.node_type else {
+            panic!("Expected a function, found {}", node.node_type);
+        };
+        assert_eq!(def.name.as_deref(), Some("add"));
+        assert_eq!(node.return_type, BaseType::Float);
+        assert_eq!(node.children.len(), 3);
+        assert_eq!(node.children[0].return_type, BaseType::Integer);
+        assert_eq!(node.children[1].return_type, BaseType::Undefined);
+        assert_eq!(node.children[2].node_type, NodeType::Block);
+
+        // Calls chain, with the function before the arguments
+        let call = &root.children[1];
+        assert_eq!(call.node_type, NodeType::Call);
+        assert_eq!(call.children.len(), 2);
+        assert_eq!(call.children[0].node_type, NodeType::Call);
+        assert_eq!(call.children[0].children.len(), 3);
+
+        assert_eq!(parse_error("return 1"), DiagnosticKind::InvalidReturn);
+
+        // Calls to functions declared with `func` must pass each parameter
+        for code in [
+            "func f(a) { a }\nf(1, 2)",
+            "func f(a, b) { a }\nf(1)",
+            "func f() { f(1) }",
+        ] {
+            assert_eq!(parse_error(code), DiagnosticKind::InvalidCall, "{code}");
+        }
+        assert_eq!(
+            parse_error("func f() { }\nf = func() { }"),
+            DiagnosticKind::InvalidAssignment
+        );
+        parse_code("func f(a) { a }\nlet g = f\ng = func(a, b) { a }\ng(1, 2)");
+    }
+
+    #[test]
+    fn test_captures() {
+        fn captures(node: &Node) -> Vec<String> {
+            match &node.node_type {
+                NodeType::Function(def) => def.captures.clone(),
+                other => panic!("Expected a function, found {other}"),
+            }
+        }
+        let code = "let g = 1
+            func outer(a) {
+                let b = 2
+                func middle() {
+                    let inner = func(c) { c + b + a + g }
+                    b
+                }
+            }";
+        let root = parse_code(code);
+        let outer = &root.children[1];
+        assert!(captures(outer).is_empty());
+        // `middle` captures what `inner` needs from `outer` so it can pass it on
+        let middle = &outer.children[1].children[1];
+        assert_eq!(captures(middle), ["b", "a"]);
+        let inner = &middle.children[0].children[0].children[0].children[0];
+        assert_eq!(captures(inner), ["b", "a"]);
+        assert!(middle.node_type.is_statement() && !inner.node_type.is_statement());
+    }
+
+    #[test]
+    fn test_annotations() {
+        let code =
+            "struct Point {}\nlet a: [int] = []\nlet p: Point\nfunc f(x: float) -> bool { x > 1 }";
+        let mut root = parse_code(code);
+        root.children.remove(0);
+        let annotation = |n: usize| {
+            let node = &root.children[n];
+            let target = match node.node_type {
+                NodeType::Let => &node.children[0],
+                _ => node,
+            };
+            target.annotation.as_ref().unwrap().to_string()
+        };
+        assert_eq!(annotation(0), "[int]");
+        assert_eq!(annotation(1), "Point");
+        assert_eq!(annotation(2), "bool");
+        let param = &root.children[2].children[0];
+        assert_eq!(
+            param.annotation,
+            Some(TypeAnnotation::Base(BaseType::Float))
+        );
+        assert_eq!(root.children[0].children[0].return_type, BaseType::Array);
+
+        // Variables take the declared type where they're used
+        let root = parse_code("let x: float = 1\nx");
+        assert_eq!(root.children[1].return_type, BaseType::Float);
+
+        for code in [
+            "let x: int = 1.5",
+            "let x: text = 1",
+            "let x: int = 1\nx = \"a\"",
+            "func f() -> text { 1 }",
+            "func f() -> int { return true }",
+            "func f(n: bool) { if n {} }\nlet y: int = 1\nif y {}",
+            "func f(x: float) { x }\nf(\"a\")",
+            "func f(a, b: int) { a }\nf(1, 2.5)",
+            "let x: int = 1\nx += 1.5",
+            "let x: int = 1\nx *= 0.5",
+            "let a: [int] = [\"x\"]",
+            "let a: [[int]] = [[1], [2.5]]",
+            "let a: [int] = [1]\nlet b: [text] = a",
+            "let a: [int] = [1]\na[0] = \"x\"",
+            "func f(a: [float]) { a }\nf([true])",
+        ] {
+            assert_eq!(parse_error(code), DiagnosticKind::TypeMismatch, "{code}");
+        }
+        assert_eq!(
+            parse_error("let b: {text: int}"),
+            DiagnosticKind::UnknownType
+        );
+    }
+
+    #[test]
+    fn test_structs() {
+        let code = "struct Point { x: float, y: float }
+            struct Line {
+                start: Point
+                end: Point
+            }
+            let p: Point = Point { y: 2, x: 1 }
+            let line = Line { start: p, end: Point { x: 0, y: 0 } }
+            if p.x > 0 { }
+            p.y";
+        let root = parse_code(code);
+        assert_eq!(
+            root.children[0].node_type,
+            NodeType::Struct(Box::new("Point".into()))
+        );
+        assert!(root.children[1].node_type.is_statement());
+
+        // The values are in declaration order
+        let literal = &root.children[2].children[0].children[0];
+        let NodeType::StructLiteral(def) = &literal.node_type else {
+            panic!("Expected a struct literal, found {}", literal.node_type);
+        };
+        assert_eq!(def.fields[1], ("y".to_string(), BaseType::Float));
+        assert_eq!(literal.children[0].node_type, NodeType::Integer(1));
+        assert_eq!(literal.return_type, BaseType::Struct);
+
+        // A struct name in a condition doesn't start a literal
+        let condition = &root.children[4].children[0];
+        assert_eq!(condition.node_type, NodeType::BinaryOp(BinOp::Gt));
+        assert_eq!(root.children[5].return_type, BaseType::Float);
+
+        for (code, kind) in [
+            ("let p: Pointy", DiagnosticKind::UnknownType),
+            ("struct P { x: int, x: int }", DiagnosticKind::InvalidField),
+            (
+                "struct P { x: int }\nP { y: 1 }",
+                DiagnosticKind::InvalidField,
+            ),
+            (
+                "struct P { x: int }\nP { x: 1, x: 2 }",
+                DiagnosticKind::InvalidField,
+            ),
+            (
+                "struct P { x: int, y: int }\nP { x: 1 }",
+                DiagnosticKind::InvalidField,
+            ),
+            (
+                "struct P { x: int }\nfunc f(p: P) { p.y }",
+                DiagnosticKind::InvalidField,
+            ),
+            (
+                "struct P { x: int }\nP { x: true }",
+                DiagnosticKind::TypeMismatch,
+            ),
+            (
+                "struct P { x: int }\nlet p: P\np.x = 1.5",
+                DiagnosticKind::TypeMismatch,
+            ),
+            (
+                "struct A { x: int }\nstruct B { y: int }\nlet a: A = B { y: 1 }",
+                DiagnosticKind::TypeMismatch,
+            ),
+            (
+                "struct A { x: int }\nstruct B { y: int }\nlet b: B\nfunc f(a: A) { a }\nf(b)",
+                DiagnosticKind::TypeMismatch,
+            ),
+            (
+                "struct A { x: int }\nstruct L { a: A }\nstruct B { y: int }\nL { a: B { y: 1 } }",
+                DiagnosticKind::TypeMismatch,
+            ),
+        ] {
+            assert_eq!(parse_error(code), kind, "{code}");
+        }
+    }
+
+    #[test]
+    fn test_enums() {
+        let code = "enum Shape {
+                Circle(float)
+                Rect(float, float),
+                Empty
+            }
+            let s: Shape = Shape.Rect(1, 2.5)
+            Shape.Empty";
+        let root = parse_code(code);
+        assert_eq!(
+            root.children[0].node_type,
+            NodeType::Enum(Box::new("Shape".into()))
+        );
+        assert!(root.children[0].node_type.is_statement());
+
+        let rect = &root.children[1].children[0].children[0];
+        let NodeType::Variant(def) = &rect.node_type else {
+            panic!("Expected a variant, found {}", rect.node_type);
+        };
+        assert_eq!((def.name.as_str(), def.tag), ("Rect", 1));
+        assert_eq!(def.payload, [BaseType::Float, BaseType::Float]);
+        assert_eq!(rect.children.len(), 2);
+        assert_eq!(rect.return_type, BaseType::Enum);
+        assert!(root.children[2].children.is_empty());
+
+        for (code, kind) in [
+            ("enum E { A, A }", DiagnosticKind::InvalidVariant),
+            ("enum E { A }\nE.B", DiagnosticKind::InvalidVariant),
+            (
+                "enum E { A(int) }\nE.A(1, 2)",
+                DiagnosticKind::InvalidVariant,
+            ),
+            (
+                "enum E { A(int) }\nE.A(\"a\")",
+                DiagnosticKind::TypeMismatch,
+            ),
+            (
+                "enum E { A }\nlet x: int = E.A",
+                DiagnosticKind::TypeMismatch,
+            ),
+            (
+                "enum E { A }\nenum F { B }\nlet e: E = F.B",
+                DiagnosticKind::TypeMismatch,
+            ),
+            (
+                "enum E { A }\nenum F { B }\nlet f: F = F.B\nlet e: E = E.A\ne = f",
+                DiagnosticKind::TypeMismatch,
+            ),
+        ] {
+            assert_eq!(parse_error(code), kind, "{code}");
+        }
+    }
+}
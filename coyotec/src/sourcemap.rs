@@ -0,0 +1,181 @@
+//! Owns the source text of every file the compiler has loaded so that spans can be
+//! turned back into lines and columns for diagnostics
+use crate::errors::{Diagnostic, Diagnostics};
+use crate::lexer::SourceType;
+use crate::tokens::{FileId, Location, Span};
+
+pub struct SourceFile {
+    pub source_type: SourceType,
+    pub source: String,
+    // Byte offset of the start of each line
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    pub fn new(source_type: SourceType, source: String) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self {
+            source_type,
+            source,
+            line_starts,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match &self.source_type {
+            SourceType::Interactive => "<repl>",
+            SourceType::Test => "<test>",
+            SourceType::File(name) => name,
+        }
+    }
+
+    /// The line and column of a byte offset. Lines start at 1 and columns count
+    /// characters from 0, the same as the lexer's `Location`.
+    pub fn location(&self, byte: usize) -> Location {
+        let byte = byte.min(self.source.len());
+        let line = self.line_starts.partition_point(|&start| start <= byte) - 1;
+        let line_start = self.line_starts[line];
+        let column = self.source[line_start..byte].chars().count();
+        Location {
+            line: line + 1,
+            column,
+        }
+    }
+
+    /// The text of a line without its line ending
+    pub fn line(&self, line: usize) -> Option<&str> {
+        let start = *self.line_starts.get(line.checked_sub(1)?)?;
+        let end = self
+            .line_starts
+            .get(line)
+            .copied()
+            .unwrap_or(self.source.len());
+        Some(self.source[start..end].trim_end_matches(['\n', '\r']))
+    }
+}
+
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self { files: Vec::new() }
+    }
+
+    /// Load a file into the map and return the id its tokens will carry
+    pub fn add(&mut self, source_type: SourceType, source: String) -> FileId {
+        self.files.push(SourceFile::new(source_type, source));
+        self.files.len() - 1
+    }
+
+    pub fn get(&self, file_id: FileId) -> Option<&SourceFile> {
+        self.files.get(file_id)
+    }
+
+    /// The start and end locations of a span. The end is the position just past the
+    /// last character.
+    pub fn locations(&self, span: Span) -> Option<(Location, Location)> {
+        let file = self.get(span.file_id)?;
+        Some((file.location(span.start_byte), file.location(span.end_byte)))
+    }
+
+    /// The source text a span covers
+    pub fn snippet(&self, span: Span) -> Option<&str> {
+        self.get(span.file_id)?
+            .source
+            .get(span.start_byte..span.end_byte)
+    }
+
+    /// Show a diagnostic with the line it was found on and the code it covers
+    /// underlined
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        let Some(file) = self.get(diagnostic.span.file_id) else {
+            return diagnostic.to_string();
+        };
+        let start = file.location(diagnostic.span.start_byte);
+        let end = file.location(diagnostic.span.end_byte);
+        let line = file.line(start.line).unwrap_or("");
+        // Spans that run onto the next line are underlined to the end of the first
+        let end_column = if end.line == start.line {
+            end.column
+        } else {
+            line.chars().count()
+        };
+        let number = start.line.to_string();
+        let gutter = " ".repeat(number.len());
+        format!(
+            "{}:{}:{}: {}\n{gutter} |\n{number} | {line}\n{gutter} | {}{}",
+            file.name(),
+            start.line,
+            start.column + 1,
+            diagnostic.message,
+            " ".repeat(start.column),
+            "^".repeat(end_column.saturating_sub(start.column).max(1)),
+        )
+    }
+
+    /// Show every diagnostic an error carries, or the error itself if it has none
+    pub fn render_error(&self, err: &anyhow::Error) -> String {
+        match err.downcast_ref::<Diagnostics>() {
+            Some(Diagnostics(diagnostics)) => diagnostics
+                .iter()
+                .map(|d| self.render(d))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            None => err.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::errors::DiagnosticKind;
+    use crate::lexer::{lex_file, LexMode};
+
+    #[test]
+    fn test_locations() {
+        let mut sources = SourceMap::new();
+        sources.add(SourceType::Test, "print 1".to_string());
+        let file_id = sources.add(
+            SourceType::Test,
+            "let x = 1\nlet é = \"a\nb\"\n".to_string(),
+        );
+        let file = sources.get(file_id).unwrap();
+
+        assert_eq!(file.location(0), Location { line: 1, column: 0 });
+        assert_eq!(file.location(10), Location { line: 2, column: 0 });
+        assert_eq!(file.location(16), Location { line: 2, column: 5 });
+        assert_eq!(file.line(2), Some("let é = \"a"));
+        assert_eq!(file.line(5), None);
+
+        let tokens = lex_file(&sources, file_id, LexMode::Normal).unwrap();
+        let text = &tokens[8];
+        assert_eq!(text.span.file_id, file_id);
+        assert_eq!(text.location, Location { line: 2, column: 8 });
+        assert_eq!(sources.snippet(text.span), Some("\"a\nb\""));
+        let (start, end) = sources.locations(text.span).unwrap();
+        assert_eq!(start, Location { line: 2, column: 8 });
+        assert_eq!(end, Location { line: 3, column: 2 });
+    }
+
+    #[test]
+    fn test_render() {
+        let mut sources = SourceMap::new();
+        let file_id = sources.add(SourceType::Test, "let x = 1\nprint x +\n".to_string());
+        let diagnostic = Diagnostic {
+            kind: DiagnosticKind::InvalidSyntax,
+            message: "Unexpected token Newline".to_string(),
+            location: Location { line: 2, column: 9 },
+            span: Span::new(file_id, 19, 20),
+        };
+        assert_eq!(
+            sources.render(&diagnostic),
+            "<test>:2:10: Unexpected token Newline\n  |\n2 | print x +\n  |          ^"
+        );
+    }
+}
@@ -3,15 +3,20 @@
 // nor do the numbers have any meaning other than being unique. This is a way to avoid carrying
 // strings in emums and avoiding clones
 
-use crate::datatypes::datatype::DataType;
+use crate::datatypes::datatype::{DataType, TypeAnnotation};
 use std::collections::HashMap;
 #[derive(Clone)]
 pub struct Item {
     pub data_type: DataType,
+    // The type the variable was declared with, if it was given one
+    pub annotation: Option<TypeAnnotation>,
 }
 impl Item {
     pub fn new(data_type: DataType) -> Self {
-        Self { data_type }
+        Self {
+            data_type,
+            annotation: None,
+        }
     }
 }
 #[derive(Clone)]
@@ -28,8 +33,8 @@ impl Symbol {
         }
     }
 
-    fn add_symbol(&mut self, name: &str, data_type: DataType) {
-        self.symbols.insert(name.to_string(), Item::new(data_type));
+    fn add_symbol(&mut self, name: &str, item: Item) {
+        self.symbols.insert(name.to_string(), item);
     }
 
     /// Get the item for a given identifier name. If the name is not in the map
@@ -39,11 +44,21 @@ impl Symbol {
     }
 }
 
+/// A function being parsed and the variables it uses from the functions around it
+#[derive(Clone)]
+struct Captures {
+    // The function's outermost scope
+    scope: usize,
+    names: Vec<String>,
+}
+
 /// A symbol table is a collection of symbol names. Each symbol name is a map of identifier
 #[derive(Clone)]
 pub struct SymbolTable {
     pub symbols: Vec<Symbol>,
     scope: usize,
+    // The functions whose scopes are open, innermost last
+    functions: Vec<Captures>,
 }
 
 impl SymbolTable {
@@ -51,18 +66,35 @@ impl SymbolTable {
         Self {
             symbols: vec![Symbol::new()],
             scope: 0,
+            functions: Vec::new(),
         }
     }
 
-    /// Get the unique number for a given identifier name. If the name is not in the map
-    /// it creates a new entry in the symbol table and returns the unique number
+    /// Get the item for a given identifier name from the innermost scope that declares
+    /// it, so inner declarations shadow outer ones
     pub fn get(&mut self, name: &str) -> Option<Item> {
-        self.symbols[self.scope].get(name)
+        let scope = self.find(name)?;
+        self.symbols[scope].get(name)
     }
 
     pub fn add_symbol(&mut self, name: &str, data_type: DataType) {
         let scope = self.scope;
-        self.symbols[scope].add_symbol(name, data_type);
+        self.symbols[scope].add_symbol(name, Item::new(data_type));
+    }
+
+    /// Add a variable that was declared with a type
+    pub fn add_annotated(&mut self, name: &str, data_type: DataType, annotation: TypeAnnotation) {
+        let scope = self.scope;
+        let item = Item {
+            data_type,
+            annotation: Some(annotation),
+        };
+        self.symbols[scope].add_symbol(name, item);
+    }
+
+    /// Get the item for a name declared outside of every block and function
+    pub fn global(&mut self, name: &str) -> Option<Item> {
+        self.symbols[0].get(name)
     }
 
     /// Push a new scope onto the symbol table
@@ -76,4 +108,66 @@ impl SymbolTable {
         self.symbols.pop();
         self.scope -= 1;
     }
+
+    /// Push the outermost scope of a function's body
+    pub fn push_function_scope(&mut self) {
+        self.push_scope();
+        self.functions.push(Captures {
+            scope: self.scope,
+            names: Vec::new(),
+        });
+    }
+
+    /// Pop the outermost scope of a function's body, returning the names of the
+    /// variables it captures in the order they were first used
+    pub fn pop_function_scope(&mut self) -> Vec<String> {
+        self.pop_scope();
+        self.functions
+            .pop()
+            .map(|captures| captures.names)
+            .unwrap_or_default()
+    }
+
+    /// The scope `name` was declared in, looking outward from the innermost one
+    fn find(&self, name: &str) -> Option<usize> {
+        (0..=self.scope)
+            .rev()
+            .find(|&scope| self.symbols[scope].symbols.contains_key(name))
+    }
+
+    /// Resolve a use of `name`. A variable declared in a block or function around
+    /// the one being parsed is captured by every function in between, so that each
+    /// can hand it on to the next. Variables outside of every block and function are
+    /// globals and are never captured.
+    pub fn capture(&mut self, name: &str) -> Option<Item> {
+        let scope = self.find(name)?;
+        if scope > 0 {
+            for function in self.functions.iter_mut().filter(|f| f.scope > scope) {
+                if !function.names.iter().any(|captured| captured == name) {
+                    function.names.push(name.to_string());
+                }
+            }
+        }
+        self.symbols[scope].get(name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scopes() {
+        let mut table = SymbolTable::new();
+        table.add_symbol("x", DataType::Integer);
+        table.push_scope();
+        // Lookups walk out to the enclosing scopes
+        assert_eq!(table.get("x").unwrap().data_type, DataType::Integer);
+        table.add_symbol("x", DataType::Text);
+        table.add_symbol("y", DataType::Float);
+        assert_eq!(table.get("x").unwrap().data_type, DataType::Text);
+        table.pop_scope();
+        assert_eq!(table.get("x").unwrap().data_type, DataType::Integer);
+        assert!(table.get("y").is_none());
+    }
 }
@@ -1,5 +1,5 @@
-use std::collections::HashMap;
 use std::fmt::Display;
+use std::rc::Rc;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum BaseType {
@@ -9,9 +9,11 @@ pub enum BaseType {
     Float,
     Text,
     Boolean,
+    Char,
     Array,
     List,
     Struct,
+    Enum,
 }
 
 impl Display for BaseType {
@@ -23,9 +25,11 @@ impl Display for BaseType {
             BaseType::Float => write!(f, "Float"),
             BaseType::Text => write!(f, "Text"),
             BaseType::Boolean => write!(f, "Boolean"),
+            BaseType::Char => write!(f, "Char"),
             BaseType::Array => write!(f, "Array"),
             BaseType::List => write!(f, "List"),
             BaseType::Struct => write!(f, "Struct"),
+            BaseType::Enum => write!(f, "Enum"),
         }
     }
 }
@@ -38,21 +42,32 @@ impl BaseType {
             BaseType::Float => "f".to_string(),
             BaseType::Text => "t".to_string(),
             BaseType::Boolean => "b".to_string(),
+            BaseType::Char => "c".to_string(),
             BaseType::Array => "a".to_string(),
             BaseType::List => "l".to_string(),
             BaseType::Struct => "s".to_string(),
+            BaseType::Enum => "e".to_string(),
             BaseType::Undefined => "u".to_string(),
         }
     }
 }
 
+/// A piece of an interpolated string: either literal text or the tokens of an
+/// embedded `${...}` expression
+#[derive(Clone, Debug, PartialEq)]
+pub enum StringPart {
+    Text(String),
+    Expr(Vec<Token>),
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum TokenType {
-    Integer(f64), // Integers are represented by floats
+    Integer(i64),
     Float(f64),
     Text(String),
+    Interpolated(Vec<StringPart>),
     Boolean(bool),
-    Struct(HashMap<String, BaseType>),
+    Char(char),
     LBracket,
     RBracket,
     LParen,
@@ -65,6 +80,7 @@ pub enum TokenType {
     SemiColon,
     Colon,
     Equal,
+    NotEqual,
     Assign,
     Plus,
     Minus,
@@ -72,24 +88,63 @@ pub enum TokenType {
     Slash,
     Percent,
     LessThan,
+    LessEqual,
     GreaterThan,
+    GreaterEqual,
     Ampersand,
+    AndAnd,
     Pipe,
+    OrOr,
+    PipeGreater,
+    Arrow,
+    FatArrow,
+    DotDot,
+    DotDotEqual,
+    StarStar,
+    PlusAssign,
+    MinusAssign,
+    StarAssign,
+    SlashAssign,
+    PercentAssign,
+    ShiftLeft,
+    ShiftRight,
     Caret,
+    Tilde,
+    TildeSlash,
     Hash,
     At,
     Question,
     Newline,
+    DocComment(String),
     Dollar,
     Quote,
     DataType(BaseType),
-    Identifier(String),
+    Identifier(Rc<str>),
+    // A loop label such as `'outer`, without the quote
+    Label(Rc<str>),
     Let,
     Func,
+    Struct,
+    Enum,
     Print,
+    If,
+    Else,
+    While,
+    For,
+    In,
+    Return,
+    Break,
+    Continue,
+    Loop,
+    And,
+    Or,
+    Not,
+    Nil,
+    // Stands in for source the lexer couldn't make sense of
+    Error,
     EOF,
 }
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Location {
     pub(crate) line: usize,
     pub(crate) column: usize,
@@ -116,15 +171,108 @@ impl Location {
     }
 }
 
-#[derive(Debug, Clone)]
+impl Location {
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn column(&self) -> usize {
+        self.column
+    }
+}
+
+/// Identifies a file loaded into a `SourceMap`
+pub type FileId = usize;
+
+/// The bytes of a file that a token or node was built from. `end_byte` is exclusive.
+/// Use `SourceMap::locations` to turn a span back into lines and columns.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Span {
+    pub file_id: FileId,
+    pub start_byte: usize,
+    pub end_byte: usize,
+}
+
+impl Span {
+    pub fn new(file_id: FileId, start_byte: usize, end_byte: usize) -> Self {
+        Self {
+            file_id,
+            start_byte,
+            end_byte,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end_byte - self.start_byte
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start_byte == self.end_byte
+    }
+
+    /// The smallest span covering both `self` and `other`, which must be in the same file
+    pub fn merge(&self, other: Span) -> Span {
+        debug_assert_eq!(self.file_id, other.file_id);
+        Span {
+            file_id: self.file_id,
+            start_byte: self.start_byte.min(other.start_byte),
+            end_byte: self.end_byte.max(other.end_byte),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TriviaKind {
+    Whitespace,
+    LineComment,
+    BlockComment,
+}
+
+/// Source text that isn't part of any token, such as whitespace and comments
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: String,
+}
+
+/// The exact text of a token along with the trivia around it. Trivia following a
+/// token on the same line is trailing, and everything else belongs to the next token
+/// as leading trivia.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TokenTrivia {
+    pub leading: Vec<Trivia>,
+    pub text: String,
+    pub trailing: Vec<Trivia>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Token {
     pub token_type: TokenType,
+    // Where the token starts
     pub location: Location,
+    pub span: Span,
+    // Only filled in when lexing with `LexMode::Lossless`
+    pub trivia: Option<Box<TokenTrivia>>,
+}
+
+/// Rebuild the source text from tokens lexed with `LexMode::Lossless`
+pub fn tokens_to_text(tokens: &[Token]) -> String {
+    let mut text = String::new();
+    for trivia in tokens.iter().filter_map(|t| t.trivia.as_ref()) {
+        for leading in &trivia.leading {
+            text.push_str(&leading.text);
+        }
+        text.push_str(&trivia.text);
+        for trailing in &trivia.trailing {
+            text.push_str(&trailing.text);
+        }
+    }
+    text
 }
 
 #[cfg(test)]
 mod test {
-    use crate::tokens::Location;
+    use crate::tokens::{Location, Span};
 
     #[test]
     fn test_location() {
@@ -133,4 +281,11 @@ mod test {
         assert_eq!(loc.column, 0);
         loc.increment(1);
     }
+
+    #[test]
+    fn test_span_merge() {
+        let span = Span::new(1, 4, 8).merge(Span::new(1, 10, 12));
+        assert_eq!(span, Span::new(1, 4, 12));
+        assert_eq!(span.len(), 8);
+    }
 }
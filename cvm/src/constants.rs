@@ -16,25 +16,99 @@ pub enum Instruction {
     Const = 12,
     Newarray = 13,
     Load = 14,
+    Concat = 15,
+    Index = 16,
+    Setindex = 17,
+    Getfield = 18,
+    Setfield = 19,
+    Neq = 20,
+    Lt = 21,
+    Le = 22,
+    Gt = 23,
+    Ge = 24,
+    Not = 25,
+    Dup = 26,
+    Jmp = 27,
+    Jmpf = 28,
+    Jmpt = 29,
+    Idiv = 30,
+    Mod = 31,
+    Pow = 32,
+    Band = 33,
+    Bor = 34,
+    Bxor = 35,
+    Shl = 36,
+    Shr = 37,
+    Bnot = 38,
+    Len = 39,
+    Range = 40,
+    Closure = 41,
+    Call = 42,
+    Ret = 43,
+    GetLocal = 44,
+    SetLocal = 45,
+    GetUpvalue = 46,
+    SetUpvalue = 47,
+    CloseUpvalue = 48,
+    Newstruct = 49,
+    Newvariant = 50,
+    Tofloat = 51,
 }
 
 impl Instruction {
-    pub const INSTRUCTIONS: [&'static str; 15] = [
-        "halt",     // 0
-        "push",     // 1
-        "add",      // 2
-        "sub",      // 3
-        "mul",      // 4
-        "div",      // 5
-        "equ",      // 6
-        "store",    // 7
-        "pop",      // 8
-        "cmp",      // 9
-        "print",    // 10
-        "neg",      // 11
-        "const",    // 12
-        "newarray", // 13
-        "load",     // 14
+    pub const INSTRUCTIONS: [&'static str; 52] = [
+        "halt",          // 0
+        "push",          // 1
+        "add",           // 2
+        "sub",           // 3
+        "mul",           // 4
+        "div",           // 5
+        "equ",           // 6
+        "store",         // 7
+        "pop",           // 8
+        "cmp",           // 9
+        "print",         // 10
+        "neg",           // 11
+        "const",         // 12
+        "newarray",      // 13
+        "load",          // 14
+        "concat",        // 15
+        "index",         // 16
+        "setindex",      // 17
+        "getfield",      // 18
+        "setfield",      // 19
+        "neq",           // 20
+        "lt",            // 21
+        "le",            // 22
+        "gt",            // 23
+        "ge",            // 24
+        "not",           // 25
+        "dup",           // 26
+        "jmp",           // 27
+        "jmpf",          // 28
+        "jmpt",          // 29
+        "idiv",          // 30
+        "mod",           // 31
+        "pow",           // 32
+        "band",          // 33
+        "bor",           // 34
+        "bxor",          // 35
+        "shl",           // 36
+        "shr",           // 37
+        "bnot",          // 38
+        "len",           // 39
+        "range",         // 40
+        "closure",       // 41
+        "call",          // 42
+        "ret",           // 43
+        "get_local",     // 44
+        "set_local",     // 45
+        "get_upvalue",   // 46
+        "set_upvalue",   // 47
+        "close_upvalue", // 48
+        "newstruct",     // 49
+        "newvariant",    // 50
+        "tofloat",       // 51
     ];
 
     /// Return the human-readable name of this instruction.
@@ -60,6 +134,43 @@ impl Instruction {
             12 => Instruction::Const,
             13 => Instruction::Newarray,
             14 => Instruction::Load,
+            15 => Instruction::Concat,
+            16 => Instruction::Index,
+            17 => Instruction::Setindex,
+            18 => Instruction::Getfield,
+            19 => Instruction::Setfield,
+            20 => Instruction::Neq,
+            21 => Instruction::Lt,
+            22 => Instruction::Le,
+            23 => Instruction::Gt,
+            24 => Instruction::Ge,
+            25 => Instruction::Not,
+            26 => Instruction::Dup,
+            27 => Instruction::Jmp,
+            28 => Instruction::Jmpf,
+            29 => Instruction::Jmpt,
+            30 => Instruction::Idiv,
+            31 => Instruction::Mod,
+            32 => Instruction::Pow,
+            33 => Instruction::Band,
+            34 => Instruction::Bor,
+            35 => Instruction::Bxor,
+            36 => Instruction::Shl,
+            37 => Instruction::Shr,
+            38 => Instruction::Bnot,
+            39 => Instruction::Len,
+            40 => Instruction::Range,
+            41 => Instruction::Closure,
+            42 => Instruction::Call,
+            43 => Instruction::Ret,
+            44 => Instruction::GetLocal,
+            45 => Instruction::SetLocal,
+            46 => Instruction::GetUpvalue,
+            47 => Instruction::SetUpvalue,
+            48 => Instruction::CloseUpvalue,
+            49 => Instruction::Newstruct,
+            50 => Instruction::Newvariant,
+            51 => Instruction::Tofloat,
             _ => {
                 panic!("Unknown opcode {}", opcode);
             }
@@ -83,7 +194,62 @@ impl Instruction {
             "const" => Some(Instruction::Const),
             "newarray" => Some(Instruction::Newarray),
             "load" => Some(Instruction::Load),
+            "concat" => Some(Instruction::Concat),
+            "index" => Some(Instruction::Index),
+            "setindex" => Some(Instruction::Setindex),
+            "getfield" => Some(Instruction::Getfield),
+            "setfield" => Some(Instruction::Setfield),
+            "neq" => Some(Instruction::Neq),
+            "lt" => Some(Instruction::Lt),
+            "le" => Some(Instruction::Le),
+            "gt" => Some(Instruction::Gt),
+            "ge" => Some(Instruction::Ge),
+            "not" => Some(Instruction::Not),
+            "dup" => Some(Instruction::Dup),
+            "jmp" => Some(Instruction::Jmp),
+            "jmpf" => Some(Instruction::Jmpf),
+            "jmpt" => Some(Instruction::Jmpt),
+            "idiv" => Some(Instruction::Idiv),
+            "mod" => Some(Instruction::Mod),
+            "pow" => Some(Instruction::Pow),
+            "band" => Some(Instruction::Band),
+            "bor" => Some(Instruction::Bor),
+            "bxor" => Some(Instruction::Bxor),
+            "shl" => Some(Instruction::Shl),
+            "shr" => Some(Instruction::Shr),
+            "bnot" => Some(Instruction::Bnot),
+            "len" => Some(Instruction::Len),
+            "range" => Some(Instruction::Range),
+            "closure" => Some(Instruction::Closure),
+            "call" => Some(Instruction::Call),
+            "ret" => Some(Instruction::Ret),
+            "get_local" => Some(Instruction::GetLocal),
+            "set_local" => Some(Instruction::SetLocal),
+            "get_upvalue" => Some(Instruction::GetUpvalue),
+            "set_upvalue" => Some(Instruction::SetUpvalue),
+            "close_upvalue" => Some(Instruction::CloseUpvalue),
+            "newstruct" => Some(Instruction::Newstruct),
+            "newvariant" => Some(Instruction::Newvariant),
+            "tofloat" => Some(Instruction::Tofloat),
             _ => None,
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_names() {
+        // Every opcode's name is the mnemonic the assembler reads back
+        for opcode in 0..Instruction::INSTRUCTIONS.len() as u8 {
+            let instruction = Instruction::from_u8(opcode);
+            assert_eq!(instruction as u8, opcode);
+            assert_eq!(
+                Instruction::match_instruction(instruction.as_str()),
+                Some(instruction)
+            );
+        }
+    }
+}
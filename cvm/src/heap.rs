@@ -1,5 +1,5 @@
 #![allow(unused_macros, dead_code, unused_imports)]
-use crate::valuetypes::Value;
+use crate::valuetypes::{Function, Object, Value};
 use std::collections::HashSet;
 use std::fmt::Display;
 
@@ -35,6 +35,21 @@ impl Heap {
     }
 }
 
+/// A variable captured by a closure. It points at a local while the frame that owns
+/// the local is running, and holds the value itself once the frame returns.
+pub enum Upvalue {
+    Open(usize),
+    Closed(Object),
+}
+
+/// A function along with the variables it captured when it was made. Every function
+/// value is a closure, even if it captured nothing.
+pub struct Closure {
+    pub function: Function,
+    // Shared with any other closure that captured the same variable
+    pub upvalues: Vec<*mut Upvalue>,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1,8 +1,8 @@
 #![allow(dead_code)]
 
+use crate::heap::Closure;
 use std::cmp::PartialEq;
 use std::fmt::{Display, Formatter};
-use std::ops::{Add, Mul};
 
 #[repr(u8)]
 #[derive(Copy, Clone, PartialEq, Debug)]
@@ -15,6 +15,11 @@ pub enum DataTag {
     Integer = 5,
     Byte = 6,
     UInt = 7,
+    Text = 8,
+    Array = 9,
+    Function = 10,
+    Struct = 11,
+    Enum = 12,
 }
 
 impl From<u8> for DataTag {
@@ -28,6 +33,11 @@ impl From<u8> for DataTag {
             5 => DataTag::Integer,
             6 => DataTag::Byte,
             7 => DataTag::UInt,
+            8 => DataTag::Text,
+            9 => DataTag::Array,
+            10 => DataTag::Function,
+            11 => DataTag::Struct,
+            12 => DataTag::Enum,
             _ => {
                 panic!("unknown tag")
             }
@@ -41,6 +51,16 @@ pub struct Object {
     pub data: Value,
 }
 
+impl Object {
+    /// Read a number as a float, converting integers
+    pub fn to_float(&self) -> f64 {
+        match self.tag {
+            DataTag::Integer => self.data.as_integer() as f64,
+            _ => self.data.as_float(),
+        }
+    }
+}
+
 impl Display for Object {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self.tag {
@@ -57,10 +77,10 @@ impl Display for Object {
                 write!(f, "*ptr")
             }
             DataTag::Char => {
-                write!(f, "{}", unsafe { self.data.byte })
+                write!(f, "{}", self.data.as_char())
             }
             DataTag::Integer => {
-                write!(f, "{}", self.data.as_float())
+                write!(f, "{}", self.data.as_integer())
             }
             DataTag::Byte => {
                 write!(f, "{}", unsafe { self.data.byte })
@@ -68,6 +88,54 @@ impl Display for Object {
             DataTag::UInt => {
                 write!(f, "{}", self.data.as_uint())
             }
+            DataTag::Text => {
+                write!(f, "{}", self.data.as_text())
+            }
+            DataTag::Array => {
+                let array = self.data.as_array();
+                write!(f, "[")?;
+                for (i, data) in array.data.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    let element = Object {
+                        tag: DataTag::from(array.data_type),
+                        data: *data,
+                    };
+                    write!(f, "{element}")?;
+                }
+                write!(f, "]")
+            }
+            DataTag::Function => {
+                let closure = self.data.as_closure();
+                write!(f, "<function/{}>", closure.function.arity)
+            }
+            DataTag::Struct => {
+                let object = self.data.as_struct();
+                write!(f, "{} {{ ", object.name)?;
+                for (i, (name, value)) in object.fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{name}: {value}")?;
+                }
+                write!(f, " }}")
+            }
+            DataTag::Enum => {
+                let variant = self.data.as_variant();
+                write!(f, "{}.{}", variant.enum_name, variant.name)?;
+                if variant.payload.is_empty() {
+                    return Ok(());
+                }
+                write!(f, "(")?;
+                for (i, value) in variant.payload.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                write!(f, ")")
+            }
         }
     }
 }
@@ -84,7 +152,7 @@ pub union Value {
 }
 impl Value {
     pub fn as_integer(&self) -> i64 {
-        unsafe { self.f as i64 }
+        unsafe { self.i }
     }
     pub fn as_float(&self) -> f64 {
         unsafe { self.f }
@@ -109,6 +177,36 @@ impl Value {
     pub fn as_byte(&self) -> u8 {
         unsafe { self.byte }
     }
+
+    /// Chars are stored as their Unicode scalar value
+    pub fn as_char(&self) -> char {
+        char::from_u32(unsafe { self.uint } as u32).unwrap_or(char::REPLACEMENT_CHARACTER)
+    }
+
+    /// Text values point at a `String` owned by the VM's heap
+    pub fn as_text(&self) -> &String {
+        unsafe { &*(self.ptr as *const String) }
+    }
+
+    /// Array values point at an `Array` owned by the VM's heap
+    pub fn as_array(&self) -> &Array {
+        unsafe { &*(self.ptr as *const Array) }
+    }
+
+    /// Function values point at a `Closure` owned by the VM's heap
+    pub fn as_closure(&self) -> &Closure {
+        unsafe { &*(self.ptr as *const Closure) }
+    }
+
+    /// Struct values point at a `Struct` owned by the VM's heap
+    pub fn as_struct(&self) -> &Struct {
+        unsafe { &*(self.ptr as *const Struct) }
+    }
+
+    /// Enum values point at a `Variant` owned by the VM's heap
+    pub fn as_variant(&self) -> &Variant {
+        unsafe { &*(self.ptr as *const Variant) }
+    }
 }
 
 #[repr(C)]
@@ -125,3 +223,35 @@ impl Array {
         }
     }
 }
+
+/// An instance of a struct. The fields are kept in the order they were declared,
+/// and each one can hold a value of any type.
+pub struct Struct {
+    pub name: String,
+    pub fields: Vec<(String, Object)>,
+}
+
+impl Struct {
+    /// The value of the field called `name`
+    pub fn field(&mut self, name: &str) -> Option<&mut Object> {
+        self.fields
+            .iter_mut()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// A value of an enum. The tag is the variant's position in the enum, and the
+/// payload holds the values the variant carries. The names are kept for printing.
+pub struct Variant {
+    pub enum_name: String,
+    pub name: String,
+    pub tag: usize,
+    pub payload: Vec<Object>,
+}
+
+/// Where a function's code starts and how many arguments it takes
+pub struct Function {
+    pub address: usize,
+    pub arity: usize,
+}
@@ -1,24 +1,74 @@
 #![allow(dead_code)]
 
-use crate::heap::Heap;
+use crate::heap::{Closure, Heap, Upvalue};
 use crate::{
     constants::Instruction,
     constants::Instruction::*,
-    valuetypes::{DataTag, Object, Value},
+    valuetypes::{Array, DataTag, Function, Object, Struct, Value, Variant},
 };
-use std::{result, usize};
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+use std::io::{Stdout, Write};
 
-pub struct Vm {
+/// A problem with the program found while running it, such as an array literal whose
+/// elements have different types
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError(pub String);
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Runtime error: {}", self.0)
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+pub struct Vm<W: Write = Stdout> {
     stack: [Object; 64000],
     sp: usize,
     heap: Heap,
+    // Variables, indexed by the register the generator gave them
+    registers: Vec<Object>,
+    // The calls in progress, innermost last
+    frames: Vec<Frame>,
+    // The locals of every frame, each frame's after its caller's
+    locals: Vec<Object>,
+    // Upvalues that still point at a local, to be closed when its frame returns
+    open_upvalues: Vec<*mut Upvalue>,
     pub code: Vec<u8>,
     string_pool: Vec<String>,
     ip: usize,
+    // Where `print` writes to
+    out: W,
+}
+
+/// A function call in progress
+struct Frame {
+    // Where to carry on once the call returns
+    return_ip: usize,
+    // Where the frame's locals start in `locals`
+    base: usize,
+    // The height of the stack before the function and its arguments were pushed
+    sp: usize,
+    // The closure being run, for its upvalues
+    closure: *const Closure,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Vm {
     pub fn new() -> Self {
+        Self::with_output(std::io::stdout())
+    }
+}
+
+impl<W: Write> Vm<W> {
+    /// Create a VM whose `print` output goes to `out` instead of stdout
+    pub fn with_output(out: W) -> Self {
         let obj = Object {
             tag: DataTag::Nil,
             data: Value { byte: 0 },
@@ -29,11 +79,20 @@ impl Vm {
             sp: 0,
             code: Vec::new(),
             heap: Heap::new(),
+            registers: Vec::new(),
+            frames: Vec::new(),
+            locals: Vec::new(),
+            open_upvalues: Vec::new(),
             string_pool: Vec::new(),
             ip: 0,
+            out,
         }
     }
 
+    pub fn output(&self) -> &W {
+        &self.out
+    }
+
     fn get_instruction(&mut self) -> Instruction {
         let byte = self.code[self.ip];
         self.ip += 1;
@@ -68,9 +127,402 @@ impl Vm {
         self.sp += 1;
     }
 
-    fn load(&mut self) {}
+    /// Push the value of the register named by the operand
+    fn load(&mut self) {
+        let reg = self.get_operand();
+        let obj = self.registers[reg];
+        self.push(obj);
+    }
+
+    /// Pop the top of the stack into the register named by the operand
+    fn store(&mut self) {
+        let reg = self.get_operand();
+        if reg >= self.registers.len() {
+            let nil = Object {
+                tag: DataTag::Nil,
+                data: Value { byte: 0 },
+            };
+            self.registers.resize(reg + 1, nil);
+        }
+        self.registers[reg] = self.pop();
+    }
+
+    /// Push a closure over the function whose code starts at the first operand and
+    /// which takes the number of arguments in the second. The third operand is the
+    /// number of variables it captures, each given by a pair of operands: 1 and a
+    /// local of the current frame, or 0 and one of the current closure's upvalues.
+    fn closure(&mut self) {
+        let address = self.get_operand();
+        let arity = self.get_operand();
+        let count = self.get_operand();
+        let mut upvalues = Vec::with_capacity(count);
+        for _ in 0..count {
+            let is_local = self.get_operand() == 1;
+            let index = self.get_operand();
+            let upvalue = if is_local {
+                self.capture_upvalue(self.frame_base() + index)
+            } else {
+                self.current_closure().upvalues[index]
+            };
+            upvalues.push(upvalue);
+        }
+        let closure = Closure {
+            function: Function { address, arity },
+            upvalues,
+        };
+        let obj = Object {
+            tag: DataTag::Function,
+            data: Value {
+                ptr: self.heap.store(closure),
+            },
+        };
+        self.push(obj);
+    }
+
+    /// The upvalue for the local at `slot`, which is shared by every closure that
+    /// captures it while its frame is running
+    fn capture_upvalue(&mut self, slot: usize) -> *mut Upvalue {
+        let existing = self
+            .open_upvalues
+            .iter()
+            .find(|&&upvalue| matches!(unsafe { &*upvalue }, Upvalue::Open(s) if *s == slot));
+        if let Some(&upvalue) = existing {
+            return upvalue;
+        }
+        let upvalue = self.heap.store(Upvalue::Open(slot)) as *mut Upvalue;
+        self.open_upvalues.push(upvalue);
+        upvalue
+    }
+
+    /// Copy the locals that `close` picks into the upvalues that point at them,
+    /// before the locals go away or are reused
+    fn close_upvalues(&mut self, close: impl Fn(usize) -> bool) {
+        let locals = &self.locals;
+        self.open_upvalues.retain(|&upvalue| {
+            let upvalue = unsafe { &mut *upvalue };
+            match *upvalue {
+                Upvalue::Open(slot) if close(slot) => {
+                    let nil = Object {
+                        tag: DataTag::Nil,
+                        data: Value { byte: 0 },
+                    };
+                    *upvalue = Upvalue::Closed(locals.get(slot).copied().unwrap_or(nil));
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+
+    /// The closure of the current frame
+    fn current_closure(&self) -> &Closure {
+        let frame = self
+            .frames
+            .last()
+            .expect("Upvalues only exist inside functions");
+        unsafe { &*frame.closure }
+    }
+
+    /// Call the function below the number of arguments given by the operand. The
+    /// arguments become the first locals of the new frame.
+    fn call(&mut self) -> Result<(), RuntimeError> {
+        let argc = self.get_operand();
+        let callee = self.stack[self.sp - argc - 1];
+        if callee.tag != DataTag::Function {
+            return Err(RuntimeError(format!(
+                "Can't call a value of type {:?}",
+                callee.tag
+            )));
+        }
+        let closure = callee.data.as_closure();
+        if closure.function.arity != argc {
+            return Err(RuntimeError(format!(
+                "The function takes {} arguments but was given {}",
+                closure.function.arity, argc
+            )));
+        }
+        let address = closure.function.address;
+
+        self.frames.push(Frame {
+            return_ip: self.ip,
+            base: self.locals.len(),
+            sp: self.sp - argc - 1,
+            closure,
+        });
+        self.locals
+            .extend_from_slice(&self.stack[self.sp - argc..self.sp]);
+        self.sp -= argc + 1;
+        self.ip = address;
+        Ok(())
+    }
+
+    /// Leave the current frame, replacing the function and its arguments with the
+    /// value on top of the stack. Anything that captured the frame's locals keeps
+    /// their last values.
+    fn ret(&mut self) {
+        let value = self.pop();
+        let frame = self.frames.pop().expect("`ret` outside of a function");
+        self.close_upvalues(|slot| slot >= frame.base);
+        self.locals.truncate(frame.base);
+        self.sp = frame.sp;
+        self.push(value);
+        self.ip = frame.return_ip;
+    }
+
+    /// Push the value of the current closure's upvalue named by the operand
+    fn get_upvalue(&mut self) {
+        let index = self.get_operand();
+        let upvalue = unsafe { &*self.current_closure().upvalues[index] };
+        let obj = match *upvalue {
+            Upvalue::Open(slot) => self.locals[slot],
+            Upvalue::Closed(obj) => obj,
+        };
+        self.push(obj);
+    }
+
+    /// Pop the top of the stack into the current closure's upvalue named by the
+    /// operand
+    fn set_upvalue(&mut self) {
+        let index = self.get_operand();
+        let obj = self.pop();
+        let upvalue = self.current_closure().upvalues[index];
+        let upvalue = unsafe { &mut *upvalue };
+        match upvalue {
+            Upvalue::Open(slot) => {
+                let slot = *slot;
+                if slot >= self.locals.len() {
+                    self.locals.resize(slot + 1, obj);
+                }
+                self.locals[slot] = obj;
+            }
+            Upvalue::Closed(value) => *value = obj,
+        }
+    }
+
+    /// Where the current frame's locals start
+    fn frame_base(&self) -> usize {
+        self.frames.last().map_or(0, |frame| frame.base)
+    }
+
+    /// Push the local of the current frame named by the operand
+    fn get_local(&mut self) {
+        let slot = self.frame_base() + self.get_operand();
+        let obj = self.locals[slot];
+        self.push(obj);
+    }
+
+    /// Pop the top of the stack into the local of the current frame named by the
+    /// operand
+    fn set_local(&mut self) {
+        let slot = self.frame_base() + self.get_operand();
+        if slot >= self.locals.len() {
+            let nil = Object {
+                tag: DataTag::Nil,
+                data: Value { byte: 0 },
+            };
+            self.locals.resize(slot + 1, nil);
+        }
+        self.locals[slot] = self.pop();
+    }
+
+    /// Pop the number of elements given by the operand into a new array. Every
+    /// element must have the same type.
+    fn new_array(&mut self) -> Result<(), RuntimeError> {
+        let len = self.get_operand();
+        let elements = self.stack[self.sp - len..self.sp].to_vec();
+        self.sp -= len;
+
+        let tag = elements.first().map_or(DataTag::Nil, |e| e.tag);
+        let mut array = Array::new(tag as u8, len);
+        for element in elements {
+            if element.tag != tag {
+                return Err(RuntimeError(format!(
+                    "Array elements must all have the same type, found {:?} and {:?}",
+                    tag, element.tag
+                )));
+            }
+            array.data.push(element.data);
+        }
+        let obj = Object {
+            tag: DataTag::Array,
+            data: Value {
+                ptr: self.heap.store(array),
+            },
+        };
+        self.push(obj);
+        Ok(())
+    }
+
+    /// Make a struct from the field values on the stack. The operands are the struct's
+    /// name, the number of fields, then the name of each field in order.
+    fn new_struct(&mut self) {
+        let name = self.get_operand();
+        let count = self.get_operand();
+        let names: Vec<usize> = (0..count).map(|_| self.get_operand()).collect();
+        let values = self.stack[self.sp - count..self.sp].to_vec();
+        self.sp -= count;
+
+        let fields = names
+            .into_iter()
+            .zip(values)
+            .map(|(field, value)| (self.string_pool[field].clone(), value))
+            .collect();
+        let object = Struct {
+            name: self.string_pool[name].clone(),
+            fields,
+        };
+        let obj = Object {
+            tag: DataTag::Struct,
+            data: Value {
+                ptr: self.heap.store(object),
+            },
+        };
+        self.push(obj);
+    }
+
+    /// Make an enum value from the values it carries, which are on the stack. The
+    /// operands are the enum's name, the variant's name, its tag and the number of
+    /// values.
+    fn new_variant(&mut self) {
+        let enum_name = self.get_operand();
+        let name = self.get_operand();
+        let tag = self.get_operand();
+        let count = self.get_operand();
+        let payload = self.stack[self.sp - count..self.sp].to_vec();
+        self.sp -= count;
+
+        let variant = Variant {
+            enum_name: self.string_pool[enum_name].clone(),
+            name: self.string_pool[name].clone(),
+            tag,
+            payload,
+        };
+        let obj = Object {
+            tag: DataTag::Enum,
+            data: Value {
+                ptr: self.heap.store(variant),
+            },
+        };
+        self.push(obj);
+    }
+
+    /// The field of a struct named by the string constant `name`
+    fn field(&mut self, object: Object, name: usize) -> Result<&mut Object, RuntimeError> {
+        let name = &self.string_pool[name];
+        if object.tag != DataTag::Struct {
+            return Err(RuntimeError(format!(
+                "Can't get field `{name}` of a value of type {:?}",
+                object.tag
+            )));
+        }
+        let object = unsafe { &mut *(object.data.as_ptr() as *mut Struct) };
+        if object.field(name).is_none() {
+            return Err(RuntimeError(format!(
+                "Struct {} has no field `{name}`",
+                object.name
+            )));
+        }
+        Ok(object.field(name).unwrap())
+    }
+
+    /// Replace a start and an end on the stack with an array of the integers from
+    /// the start up to but not including the end
+    fn range(&mut self) -> Result<(), RuntimeError> {
+        let start = self.pop();
+        let end = self.pop();
+        if start.tag != DataTag::Integer || end.tag != DataTag::Integer {
+            return Err(RuntimeError(format!(
+                "A range needs integers, found {:?} and {:?}",
+                start.tag, end.tag
+            )));
+        }
+        let (start, end) = (start.data.as_integer(), end.data.as_integer());
+        let mut array = Array::new(
+            DataTag::Integer as u8,
+            end.saturating_sub(start).max(0) as usize,
+        );
+        array.data.extend((start..end).map(|i| Value { i }));
+        let obj = Object {
+            tag: DataTag::Array,
+            data: Value {
+                ptr: self.heap.store(array),
+            },
+        };
+        self.push(obj);
+        Ok(())
+    }
+
+    /// Replace an array on the stack with its number of elements
+    fn length(&mut self) -> Result<(), RuntimeError> {
+        let obj = self.pop();
+        if obj.tag != DataTag::Array {
+            return Err(RuntimeError(format!(
+                "Can't take the length of a value of type {:?}",
+                obj.tag
+            )));
+        }
+        self.push(Object {
+            tag: DataTag::Integer,
+            data: Value {
+                i: obj.data.as_array().data.len() as i64,
+            },
+        });
+        Ok(())
+    }
+
+    /// Pop an index and an array and check the index is in bounds
+    fn pop_element(&mut self) -> Result<(&mut Array, usize), RuntimeError> {
+        let index = self.pop();
+        let array = self.pop();
+        if array.tag != DataTag::Array {
+            return Err(RuntimeError(format!(
+                "Can't index into a value of type {:?}",
+                array.tag
+            )));
+        }
+        if index.tag != DataTag::Integer {
+            return Err(RuntimeError(format!(
+                "Array index must be an integer, found {:?}",
+                index.tag
+            )));
+        }
+        let array = unsafe { &mut *(array.data.as_ptr() as *mut Array) };
+        let i = index.data.as_integer();
+        if i < 0 || i as usize >= array.data.len() {
+            return Err(RuntimeError(format!(
+                "Index {} is out of bounds for an array of length {}",
+                i,
+                array.data.len()
+            )));
+        }
+        Ok((array, i as usize))
+    }
+
+    /// Replace an array and index on the stack with the element they point at
+    fn index(&mut self) -> Result<(), RuntimeError> {
+        let (array, i) = self.pop_element()?;
+        let obj = Object {
+            tag: DataTag::from(array.data_type),
+            data: array.data[i],
+        };
+        self.push(obj);
+        Ok(())
+    }
 
-    fn store(&mut self) {}
+    /// Pop a value, an index and an array and store the value in the array
+    fn set_index(&mut self) -> Result<(), RuntimeError> {
+        let value = self.pop();
+        let (array, i) = self.pop_element()?;
+        if value.tag as u8 != array.data_type {
+            return Err(RuntimeError(format!(
+                "Can't store a {:?} in an array of {:?}",
+                value.tag,
+                DataTag::from(array.data_type)
+            )));
+        }
+        array.data[i] = value.data;
+        Ok(())
+    }
     /// Gets the value that exists following the `const` instruction
     fn get_const(&mut self) -> Object {
         // The const has already been consumed so the next byte tells us the type
@@ -82,6 +534,22 @@ impl Vm {
         }
     }
 
+    /// Gets an operand such as a string pool index. Operands are encoded the same
+    /// way as constants
+    fn get_operand(&mut self) -> usize {
+        self.get_const().data.as_integer() as usize
+    }
+
+    /// Allocates a string on the heap and wraps it in a text object
+    fn new_text(&mut self, s: String) -> Object {
+        Object {
+            tag: DataTag::Text,
+            data: Value {
+                ptr: self.heap.store(s),
+            },
+        }
+    }
+
     /// Loads constants from the ASM file that need to go into the string pool
     fn load_string_pool(&mut self) {
         self.string_pool.clear();
@@ -97,38 +565,90 @@ impl Vm {
         }
     }
 
-    pub fn run(&mut self) {
+    /// Run the code. If it stops with an error, the calls in progress are abandoned
+    /// so the VM can run more code afterwards.
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
+        let result = self.run_code();
+        if result.is_err() {
+            self.close_upvalues(|_| true);
+            self.frames.clear();
+            self.locals.clear();
+            self.sp = 0;
+        }
+        result
+    }
+
+    fn run_code(&mut self) -> Result<(), RuntimeError> {
         println!("Loading constants");
         self.ip = 0;
         self.load_string_pool();
         println!("Executing code ..");
 
         macro_rules! binop {
-            ($op:tt) => {
+            ($op:tt, $int_op:ident) => {
 
                 let left = self.pop();
                 let right = self.pop();
 
-                let left_tag = left.tag;
-                let right_tag = right.tag;
-
-                let result_value = left.data.as_float() $op right.data.as_float();
-
-                let result_tag = match (left_tag, right_tag) {
-                    (DataTag::Integer, DataTag::Integer) => DataTag::Integer,
-                    (DataTag::Float, _) | (_, DataTag::Float) => DataTag::Float,
-                    _ => panic!("Tag combination {:?} and {:?} are not allowed", left_tag, right_tag),
-                };
-
-                let result = Object {
-                    tag: result_tag,
-                    data: Value { f: result_value },
+                let result = match (left.tag, right.tag) {
+                    (DataTag::Integer, DataTag::Integer) => {
+                        let value = left
+                            .data
+                            .as_integer()
+                            .$int_op(right.data.as_integer())
+                            .ok_or_else(|| {
+                                RuntimeError(format!("Invalid integer operation: {} {} {}", left, stringify!($op), right))
+                            })?;
+                        Object {
+                            tag: DataTag::Integer,
+                            data: Value { i: value },
+                        }
+                    }
+                    (DataTag::Integer | DataTag::Float, DataTag::Integer | DataTag::Float) => {
+                        Object {
+                            tag: DataTag::Float,
+                            data: Value { f: left.to_float() $op right.to_float() },
+                        }
+                    }
+                    (left_tag, right_tag) => {
+                        return Err(RuntimeError(format!("Tag combination {:?} and {:?} are not allowed", left_tag, right_tag)));
+                    }
                 };
 
                 self.push(result);
             };
         }
 
+        // Operators that only work on integers
+        macro_rules! int_binop {
+            ($op:tt, $int_op:expr) => {
+                let left = self.pop();
+                let right = self.pop();
+                if left.tag != DataTag::Integer || right.tag != DataTag::Integer {
+                    return Err(RuntimeError(format!(
+                        "`{}` only works on integers, found {:?} and {:?}",
+                        stringify!($op),
+                        left.tag,
+                        right.tag
+                    )));
+                }
+                let f: fn(i64, i64) -> Option<i64> = $int_op;
+                let value =
+                    f(left.data.as_integer(), right.data.as_integer()).ok_or_else(|| {
+                        RuntimeError(format!(
+                            "Invalid integer operation: {} {} {}",
+                            left,
+                            stringify!($op),
+                            right
+                        ))
+                    })?;
+                self.push(Object {
+                    tag: DataTag::Integer,
+                    data: Value { i: value },
+                });
+            };
+        }
+
         println!("\nVM Debug");
         println!("--------");
         loop {
@@ -144,37 +664,256 @@ impl Vm {
                     self.push(obj);
                 }
 
+                Const => {
+                    let index = self.get_operand();
+                    let obj = self.new_text(self.string_pool[index].clone());
+                    self.push(obj);
+                }
+
+                Concat => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    let obj = self.new_text(format!("{left}{right}"));
+                    self.push(obj);
+                }
+
                 Add => {
-                    binop!(+);
+                    binop!(+, checked_add);
                 }
 
                 Sub => {
-                    binop!(-);
+                    binop!(-, checked_sub);
                 }
 
                 Mul => {
-                    binop!(*);
+                    binop!(*, checked_mul);
                 }
 
                 Div => {
-                    binop!(/);
+                    binop!(/, checked_div);
                 }
 
-                Neg => {}
+                Idiv => {
+                    self.int_div()?;
+                }
+
+                Mod => {
+                    self.modulo()?;
+                }
+
+                Pow => {
+                    self.pow()?;
+                }
+
+                Band => {
+                    int_binop!(&, |a, b| Some(a & b));
+                }
+
+                Bor => {
+                    int_binop!(|, |a, b| Some(a | b));
+                }
+
+                Bxor => {
+                    int_binop!(^, |a, b| Some(a ^ b));
+                }
+
+                Shl => {
+                    int_binop!(<<, |a, b| u32::try_from(b).ok().and_then(|b| a.checked_shl(b)));
+                }
+
+                Shr => {
+                    int_binop!(>>, |a, b| u32::try_from(b).ok().and_then(|b| a.checked_shr(b)));
+                }
+
+                Bnot => {
+                    let obj = self.pop();
+                    if obj.tag != DataTag::Integer {
+                        return Err(RuntimeError(format!(
+                            "`~` only works on integers, found {:?}",
+                            obj.tag
+                        )));
+                    }
+                    self.push(Object {
+                        tag: DataTag::Integer,
+                        data: Value {
+                            i: !obj.data.as_integer(),
+                        },
+                    });
+                }
+
+                Equ => {
+                    let left = self.pop();
+                    let right = self.pop();
+                    self.push_bool(equals(&left, &right));
+                }
+
+                Neq => {
+                    let left = self.pop();
+                    let right = self.pop();
+                    self.push_bool(!equals(&left, &right));
+                }
+
+                Lt => {
+                    self.comparison(|o| o == Ordering::Less)?;
+                }
+
+                Le => {
+                    self.comparison(|o| o != Ordering::Greater)?;
+                }
+
+                Gt => {
+                    self.comparison(|o| o == Ordering::Greater)?;
+                }
+
+                Ge => {
+                    self.comparison(|o| o != Ordering::Less)?;
+                }
+
+                Neg => {
+                    let obj = self.pop();
+                    let data = match obj.tag {
+                        DataTag::Integer => Value {
+                            i: obj.data.as_integer().wrapping_neg(),
+                        },
+                        DataTag::Float => Value {
+                            f: -obj.data.as_float(),
+                        },
+                        _ => {
+                            return Err(RuntimeError(format!(
+                                "Can't negate a value of type {:?}",
+                                obj.tag
+                            )))
+                        }
+                    };
+                    self.push(Object { tag: obj.tag, data });
+                }
+
+                Newarray => {
+                    self.new_array()?;
+                }
+
+                Index => {
+                    self.index()?;
+                }
+
+                Len => {
+                    self.length()?;
+                }
+
+                Range => {
+                    self.range()?;
+                }
+
+                Setindex => {
+                    self.set_index()?;
+                }
+
+                Newstruct => {
+                    self.new_struct();
+                }
+
+                Newvariant => {
+                    self.new_variant();
+                }
+
+                Tofloat => {
+                    self.convert_to_float()?;
+                }
+
+                Getfield => {
+                    let name = self.get_operand();
+                    let object = self.pop();
+                    let value = *self.field(object, name)?;
+                    self.push(value);
+                }
+
+                Setfield => {
+                    let name = self.get_operand();
+                    let value = self.pop();
+                    let object = self.pop();
+                    *self.field(object, name)? = value;
+                }
+
+                Store => {
+                    self.store();
+                }
+
+                Instruction::Closure => {
+                    self.closure();
+                }
+
+                Call => {
+                    self.call()?;
+                }
+
+                Ret => {
+                    self.ret();
+                }
+
+                GetLocal => {
+                    self.get_local();
+                }
 
-                Newarray => {}
-                Store => {}
+                SetLocal => {
+                    self.set_local();
+                }
 
-                Pop => {}
+                CloseUpvalue => {
+                    // The local's scope has ended
+                    let local = self.frame_base() + self.get_operand();
+                    self.close_upvalues(|slot| slot == local);
+                }
+
+                GetUpvalue => {
+                    self.get_upvalue();
+                }
+
+                SetUpvalue => {
+                    self.set_upvalue();
+                }
+
+                Pop => {
+                    self.pop();
+                }
+
+                Dup => {
+                    let obj = self.stack[self.sp - 1];
+                    self.push(obj);
+                }
+
+                Not => {
+                    let b = self.pop_bool("`not`")?;
+                    self.push_bool(!b);
+                }
+
+                Jmp => {
+                    self.ip = self.get_operand();
+                }
+
+                Jmpf => {
+                    let target = self.get_operand();
+                    if !self.pop_bool("A condition")? {
+                        self.ip = target;
+                    }
+                }
+
+                Jmpt => {
+                    let target = self.get_operand();
+                    if self.pop_bool("A condition")? {
+                        self.ip = target;
+                    }
+                }
 
                 Print => {
                     self.print();
                 }
 
-                Halt => unsafe {
-                    println!("\n{}", self.pop());
+                Halt => {
+                    if self.sp > 0 {
+                        println!("\n{}", self.pop());
+                    }
                     break;
-                },
+                }
                 _ => {
                     println!("Unknown instruction: {}", b.as_str());
                     break;
@@ -188,23 +927,262 @@ impl Vm {
         }
 
         //println!("{}", self.registers[0].as_integer());
+        Ok(())
+    }
+
+    /// Divide and round down. Two integers give an integer, and anything else gives
+    /// a whole float.
+    fn int_div(&mut self) -> Result<(), RuntimeError> {
+        let left = self.pop();
+        let right = self.pop();
+        let result = match (left.tag, right.tag) {
+            (DataTag::Integer, DataTag::Integer) => {
+                let (a, b) = (left.data.as_integer(), right.data.as_integer());
+                let value = a
+                    .checked_div(b)
+                    .map(|q| {
+                        if a % b != 0 && (a < 0) != (b < 0) {
+                            q - 1
+                        } else {
+                            q
+                        }
+                    })
+                    .ok_or_else(|| {
+                        RuntimeError(format!("Invalid integer operation: {left} ~/ {right}"))
+                    })?;
+                Object {
+                    tag: DataTag::Integer,
+                    data: Value { i: value },
+                }
+            }
+            (DataTag::Integer | DataTag::Float, DataTag::Integer | DataTag::Float) => Object {
+                tag: DataTag::Float,
+                data: Value {
+                    f: (left.to_float() / right.to_float()).floor(),
+                },
+            },
+            (left_tag, right_tag) => {
+                return Err(RuntimeError(format!(
+                    "Tag combination {:?} and {:?} are not allowed",
+                    left_tag, right_tag
+                )))
+            }
+        };
+        self.push(result);
+        Ok(())
+    }
+
+    /// The remainder after dividing with `~/`, so it has the sign of the right
+    /// operand. Two integers give an integer, and anything else gives a float.
+    fn modulo(&mut self) -> Result<(), RuntimeError> {
+        let left = self.pop();
+        let right = self.pop();
+        let result = match (left.tag, right.tag) {
+            (DataTag::Integer, DataTag::Integer) => {
+                let (a, b) = (left.data.as_integer(), right.data.as_integer());
+                let value = a
+                    .checked_rem(b)
+                    .map(|r| {
+                        if r != 0 && (r < 0) != (b < 0) {
+                            r + b
+                        } else {
+                            r
+                        }
+                    })
+                    .ok_or_else(|| {
+                        RuntimeError(format!("Invalid integer operation: {left} % {right}"))
+                    })?;
+                Object {
+                    tag: DataTag::Integer,
+                    data: Value { i: value },
+                }
+            }
+            (DataTag::Integer | DataTag::Float, DataTag::Integer | DataTag::Float) => {
+                let (a, b) = (left.to_float(), right.to_float());
+                let r = a % b;
+                let value = if r != 0.0 && (r < 0.0) != (b < 0.0) {
+                    r + b
+                } else {
+                    r
+                };
+                Object {
+                    tag: DataTag::Float,
+                    data: Value { f: value },
+                }
+            }
+            (left_tag, right_tag) => {
+                return Err(RuntimeError(format!(
+                    "Tag combination {:?} and {:?} are not allowed",
+                    left_tag, right_tag
+                )))
+            }
+        };
+        self.push(result);
+        Ok(())
+    }
+
+    /// Raise to a power. An integer raised to a non-negative integer stays an integer.
+    fn pow(&mut self) -> Result<(), RuntimeError> {
+        let left = self.pop();
+        let right = self.pop();
+        let result = match (left.tag, right.tag) {
+            (DataTag::Integer, DataTag::Integer) if right.data.as_integer() >= 0 => {
+                let value = u32::try_from(right.data.as_integer())
+                    .ok()
+                    .and_then(|exp| left.data.as_integer().checked_pow(exp))
+                    .ok_or_else(|| {
+                        RuntimeError(format!("Invalid integer operation: {left} ** {right}"))
+                    })?;
+                Object {
+                    tag: DataTag::Integer,
+                    data: Value { i: value },
+                }
+            }
+            (DataTag::Integer | DataTag::Float, DataTag::Integer | DataTag::Float) => Object {
+                tag: DataTag::Float,
+                data: Value {
+                    f: left.to_float().powf(right.to_float()),
+                },
+            },
+            (left_tag, right_tag) => {
+                return Err(RuntimeError(format!(
+                    "Tag combination {:?} and {:?} are not allowed",
+                    left_tag, right_tag
+                )))
+            }
+        };
+        self.push(result);
+        Ok(())
+    }
+
+    /// Turn the integer on top of the stack into a float. A float is left as it is,
+    /// and anything else can't be used where a float is needed.
+    fn convert_to_float(&mut self) -> Result<(), RuntimeError> {
+        let obj = self.pop();
+        if !matches!(obj.tag, DataTag::Integer | DataTag::Float) {
+            return Err(RuntimeError(format!(
+                "Expected a float, found {:?}",
+                obj.tag
+            )));
+        }
+        self.push(Object {
+            tag: DataTag::Float,
+            data: Value { f: obj.to_float() },
+        });
+        Ok(())
+    }
+
+    fn push_bool(&mut self, b: bool) {
+        self.push(Object {
+            tag: DataTag::Bool,
+            data: Value { b },
+        });
+    }
+
+    /// Pop a value that must be a boolean. `what` names the thing that needs it
+    /// for the error message.
+    fn pop_bool(&mut self, what: &str) -> Result<bool, RuntimeError> {
+        let obj = self.pop();
+        if obj.tag != DataTag::Bool {
+            return Err(RuntimeError(format!(
+                "{what} must be a boolean, found {:?}",
+                obj.tag
+            )));
+        }
+        Ok(obj.data.as_bool())
+    }
+
+    /// Pop two values and push whether their ordering is one that `accept` allows.
+    /// Comparisons involving NaN are always false.
+    fn comparison(&mut self, accept: fn(Ordering) -> bool) -> Result<(), RuntimeError> {
+        let left = self.pop();
+        let right = self.pop();
+        let result = order(&left, &right)?.is_some_and(accept);
+        self.push_bool(result);
+        Ok(())
     }
 
     fn print(&mut self) {
-        let value = self.stack[self.sp];
+        let value = self.pop();
+        writeln!(self.out, "{value}").expect("Unable to write output");
+    }
+}
+
+/// Order two values. Integers and floats can be mixed, and text, chars and
+/// booleans compare with values of their own type.
+fn order(left: &Object, right: &Object) -> Result<Option<Ordering>, RuntimeError> {
+    let ordering = match (left.tag, right.tag) {
+        (DataTag::Integer, DataTag::Integer) => {
+            Some(left.data.as_integer().cmp(&right.data.as_integer()))
+        }
+        (DataTag::Integer | DataTag::Float, DataTag::Integer | DataTag::Float) => {
+            left.to_float().partial_cmp(&right.to_float())
+        }
+        (DataTag::Text, DataTag::Text) => Some(left.data.as_text().cmp(right.data.as_text())),
+        (DataTag::Char, DataTag::Char) => Some(left.data.as_char().cmp(&right.data.as_char())),
+        (DataTag::Bool, DataTag::Bool) => Some(left.data.as_bool().cmp(&right.data.as_bool())),
+        (DataTag::Nil, DataTag::Nil) => Some(Ordering::Equal),
+        (left_tag, right_tag) => {
+            return Err(RuntimeError(format!(
+                "Can't compare {:?} with {:?}",
+                left_tag, right_tag
+            )))
+        }
+    };
+    Ok(ordering)
+}
+
+/// Whether two values are equal. Values that can't be ordered against each other,
+/// such as an integer and some text, are never equal, so `nil` only equals itself.
+/// Arrays, structs and enum values are equal if what they hold is, and a function
+/// only equals itself.
+fn equals(left: &Object, right: &Object) -> bool {
+    match (left.tag, right.tag) {
+        (DataTag::Array, DataTag::Array) => {
+            let (left, right) = (left.data.as_array(), right.data.as_array());
+            let element = |array: &Array, data: Value| Object {
+                tag: DataTag::from(array.data_type),
+                data,
+            };
+            left.data.len() == right.data.len()
+                && left
+                    .data
+                    .iter()
+                    .zip(&right.data)
+                    .all(|(&l, &r)| equals(&element(left, l), &element(right, r)))
+        }
+        (DataTag::Struct, DataTag::Struct) => {
+            let (left, right) = (left.data.as_struct(), right.data.as_struct());
+            left.name == right.name
+                && left
+                    .fields
+                    .iter()
+                    .zip(&right.fields)
+                    .all(|((_, l), (_, r))| equals(l, r))
+        }
+        (DataTag::Enum, DataTag::Enum) => {
+            let (left, right) = (left.data.as_variant(), right.data.as_variant());
+            left.enum_name == right.enum_name
+                && left.tag == right.tag
+                && left
+                    .payload
+                    .iter()
+                    .zip(&right.payload)
+                    .all(|(l, r)| equals(l, r))
+        }
+        (DataTag::Function, DataTag::Function) => left.data.as_ptr() == right.data.as_ptr(),
+        _ => matches!(order(left, right), Ok(Some(Ordering::Equal))),
     }
 }
 
-pub fn execute(bytecode: Vec<u8>) {
+pub fn execute(bytecode: Vec<u8>) -> Result<(), RuntimeError> {
     let mut vm = Vm::new();
     vm.code = bytecode;
-    vm.run();
+    vm.run()
 }
 
 #[cfg(test)]
 mod test {
-    use super::*;
-    use crate::vm::Vm;
     #[test]
     fn test_vm() {}
 
@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 use cvm::constants::*;
 use cvm::valuetypes::DataTag;
+use std::collections::HashMap;
 use std::iter::Peekable;
 use std::str::Chars;
 
@@ -8,6 +9,10 @@ struct AsmParser<'a> {
     asm: Peekable<Chars<'a>>,
     line: usize,
     bytecode: Vec<u8>,
+    // Where each label was defined
+    labels: HashMap<String, usize>,
+    // Operands that refer to a label, to be filled in once every label is known
+    fixups: Vec<(usize, String)>,
 }
 
 impl<'a> AsmParser<'a> {
@@ -16,6 +21,8 @@ impl<'a> AsmParser<'a> {
             asm: asm.chars().peekable(),
             line: 1,
             bytecode: Vec::new(),
+            labels: HashMap::new(),
+            fixups: Vec::new(),
         }
     }
 
@@ -23,7 +30,8 @@ impl<'a> AsmParser<'a> {
         let mut s = String::new();
 
         while let Some(&d) = self.asm.peek() {
-            if d.is_numeric() || d == '.' {
+            let is_exponent_sign = (d == '-' || d == '+') && s.ends_with(['e', 'E']);
+            if d.is_ascii_digit() || d == '.' || d == 'e' || d == 'E' || is_exponent_sign {
                 s.push(d);
                 self.advance();
             } else {
@@ -62,6 +70,27 @@ impl<'a> AsmParser<'a> {
         line
     }
 
+    /// Read a quoted literal, quotes and escapes included, up to the unescaped quote
+    /// that matches the opening one
+    fn get_quoted(&mut self) -> String {
+        let mut literal = String::new();
+        let Some(quote) = self.advance() else {
+            return literal;
+        };
+        literal.push(quote);
+        while let Some(c) = self.advance() {
+            literal.push(c);
+            if c == '\\' {
+                if let Some(escaped) = self.advance() {
+                    literal.push(escaped);
+                }
+            } else if c == quote {
+                break;
+            }
+        }
+        literal
+    }
+
     fn write_constants(&mut self) {
         // Load all the constants after the .constants directive
         let mut const_count: u32 = 0;
@@ -71,7 +100,10 @@ impl<'a> AsmParser<'a> {
             if line == ".end" {
                 break;
             }
-            if line == "" {
+            if line.is_empty() {
+                if self.asm.peek().is_none() {
+                    break;
+                }
                 continue;
             }
 
@@ -80,7 +112,7 @@ impl<'a> AsmParser<'a> {
             // .append(&mut const_count.to_le_bytes().to_vec());
 
             // Add the length of the string as a 4 byte integer
-            let constant = line.trim();
+            let constant = unquote(line.trim());
             let mut const_bytes = constant.as_bytes().to_vec();
             let const_len: u32 = const_bytes.len() as u32;
             self.bytecode.append(&mut const_len.to_le_bytes().to_vec());
@@ -127,12 +159,20 @@ impl<'a> AsmParser<'a> {
                 }
             }
 
+            if c == '\'' {
+                let literal = self.get_quoted();
+                let value = unquote(&literal).chars().next().unwrap_or('\0');
+                self.bytecode.push(DataTag::Char as u8);
+                self.emit_operand((value as u64).to_le_bytes());
+                continue;
+            }
+
             if c.is_alphabetic() {
                 self.advance();
                 let mut s = String::new();
                 s.push(c);
                 while let Some(&c) = self.asm.peek() {
-                    if c.is_alphabetic() {
+                    if c.is_alphanumeric() || c == '_' {
                         s.push(c);
                         self.advance();
                     } else {
@@ -140,29 +180,66 @@ impl<'a> AsmParser<'a> {
                     }
                 }
 
+                // A label marks the position of the next instruction
+                if self.asm.peek() == Some(&':') {
+                    self.advance();
+                    self.labels.insert(s, self.bytecode.len());
+                    continue;
+                }
+
                 if let Some(byte) = self.match_keyword(&s) {
                     self.emit_command(byte as u8);
                     continue;
                 }
+
+                match s.as_str() {
+                    "true" | "false" => {
+                        self.bytecode.push(DataTag::Bool as u8);
+                        self.emit_operand(((s == "true") as u64).to_le_bytes());
+                    }
+                    "nil" => {
+                        self.bytecode.push(DataTag::Nil as u8);
+                        self.emit_operand([0; 8]);
+                    }
+                    // Anything else is a jump target
+                    _ => {
+                        self.bytecode.push(DataTag::Integer as u8);
+                        self.fixups.push((self.bytecode.len(), s));
+                        self.emit_operand([0; 8]);
+                    }
+                }
+                continue;
             }
 
             if c.is_ascii_digit() {
                 let s = self.make_number_string();
 
-                if s.contains('.') {
+                let num = if s.contains(['.', 'e', 'E']) {
                     self.bytecode.push(DataTag::Float as u8);
+                    s.parse::<f64>().unwrap().to_le_bytes()
                 } else {
                     self.bytecode.push(DataTag::Integer as u8);
+                    s.parse::<i64>().unwrap().to_le_bytes()
                 };
-                let num = s.parse::<f64>().unwrap().to_le_bytes();
                 self.emit_operand(num);
                 continue;
             }
         }
         self.bytecode.push(0x00);
+        self.resolve_labels();
         self.bytecode.clone()
     }
 
+    /// Fill in the operands that refer to labels with the labels' positions
+    fn resolve_labels(&mut self) {
+        for (position, label) in std::mem::take(&mut self.fixups) {
+            let Some(&target) = self.labels.get(&label) else {
+                panic!("Unknown label `{label}`");
+            };
+            self.bytecode[position..position + 8].copy_from_slice(&target.to_le_bytes());
+        }
+    }
+
     fn emit_command(&mut self, code: u8) {
         self.bytecode.push(code);
     }
@@ -186,6 +263,48 @@ impl<'a> AsmParser<'a> {
     }
 }
 
+/// Constants are written as quoted strings with escapes so that they can hold
+/// newlines and surrounding spaces. Char operands are quoted the same way with `'`.
+/// Unquoted constants are taken as they are.
+fn unquote(constant: &str) -> String {
+    let inner = ['"', '\''].into_iter().find_map(|quote| {
+        constant
+            .strip_prefix(quote)
+            .and_then(|c| c.strip_suffix(quote))
+    });
+    let Some(inner) = inner else {
+        return constant.to_string();
+    };
+
+    let mut s = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            s.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => s.push('\n'),
+            Some('t') => s.push('\t'),
+            Some('r') => s.push('\r'),
+            Some('0') => s.push('\0'),
+            Some('u') => {
+                let hex: String = chars
+                    .by_ref()
+                    .skip_while(|&x| x == '{')
+                    .take_while(|&x| x != '}')
+                    .collect();
+                if let Some(x) = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                    s.push(x);
+                }
+            }
+            Some(x) => s.push(x),
+            None => {}
+        }
+    }
+    s
+}
+
 pub fn assemble(asm: &str) -> Vec<u8> {
     let mut parser = AsmParser::new(asm);
     parser.assemble()
@@ -197,4 +316,52 @@ mod test {
 
     #[test]
     fn test_asm_parser() {}
+
+    #[test]
+    fn test_number_operands() {
+        let bytecode = assemble("push 9007199254740993 ;\npush 1.5e-3 ;");
+        assert_eq!(bytecode[0], Instruction::Push as u8);
+        assert_eq!(bytecode[1], DataTag::Integer as u8);
+        let int = i64::from_le_bytes(bytecode[2..10].try_into().unwrap());
+        assert_eq!(int, 9_007_199_254_740_993);
+
+        assert_eq!(bytecode[10], Instruction::Push as u8);
+        assert_eq!(bytecode[11], DataTag::Float as u8);
+        let float = f64::from_le_bytes(bytecode[12..20].try_into().unwrap());
+        assert_eq!(float, 1.5e-3);
+    }
+
+    #[test]
+    fn test_char_operands() {
+        let bytecode = assemble("push 'a' ;\npush '\\'' ;\npush '\\u{1F600}' ;");
+        let chars: Vec<_> = bytecode[..30]
+            .chunks(10)
+            .map(|op| {
+                assert_eq!(op[1], DataTag::Char as u8);
+                u64::from_le_bytes(op[2..10].try_into().unwrap())
+            })
+            .collect();
+        assert_eq!(chars, ['a', '\'', '\u{1F600}'].map(|c| c as u64));
+    }
+
+    #[test]
+    fn test_labels() {
+        let bytecode = assemble(".constants\n.end\njmp done ;\nback:\npop ;\ndone:\njmp back ;");
+        let operand = |at: usize| u64::from_le_bytes(bytecode[at..at + 8].try_into().unwrap());
+        // The code starts after the 4 byte count of constants
+        assert_eq!(bytecode[4], Instruction::Jmp as u8);
+        assert_eq!(bytecode[5], DataTag::Integer as u8);
+        assert_eq!(operand(6), 15);
+        assert_eq!(bytecode[14], Instruction::Pop as u8);
+        assert_eq!(bytecode[15], Instruction::Jmp as u8);
+        assert_eq!(operand(17), 14);
+    }
+
+    #[test]
+    fn test_unquote() {
+        assert_eq!(unquote(r#""Hello, ""#), "Hello, ");
+        assert_eq!(unquote(r#""a\n\"b\"\\""#), "a\n\"b\"\\");
+        assert_eq!(unquote(r#""\u{1b}[0m""#), "\u{1b}[0m");
+        assert_eq!(unquote("plain"), "plain");
+    }
 }